@@ -0,0 +1,149 @@
+//! # //! Error type of the library
+//!
+//! **Author**: "Dany LE"
+//!
+use crate::tunnel::Msg;
+use std::ffi::NulError;
+use std::fmt;
+use std::str::Utf8Error;
+
+/// Result type of the library
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// All the errors returned by the library
+///
+#[derive(Debug)]
+pub enum Error {
+    /// IO error on the tunnel socket, a file or the poll object
+    Io(std::io::Error),
+    /// Unexpected number read from a frame (magic begin/end)
+    Protocol { expected: u16, got: u16 },
+    /// Unsupported message kind read from a frame
+    InvalidKind(u8),
+    /// Frame payload size above the accepted limit
+    PayloadTooLarge { size: usize, max: usize },
+    /// The tunnel service refused to open the channel
+    ChannelRefused(Msg),
+    /// The tunnel socket has been closed by the tunnel service
+    Closed,
+    /// Nothing has been received from the tunnel service within
+    /// the liveness timeout of the heartbeat
+    Unresponsive(std::time::Duration),
+    /// The topic is not connected to the tunnel
+    NotConnected,
+    /// The outbound queue is above its high-water mark
+    Congested,
+    /// The client is not subscribed to the channel
+    NotSubscribed(u16),
+    /// The topic has been dropped
+    TopicDropped,
+    /// Invalid RPC envelope or unknown RPC method
+    Rpc(String),
+    /// No response before a timeout (RPC request, reconnection handshake)
+    Timeout,
+    /// Error reply to an RPC request
+    Remote(String),
+    /// Unable to serialize or deserialize a typed payload
+    Serde(String),
+    /// Unable to compress or decompress a payload
+    Compression(String),
+    /// Unable to reconnect the topic after a number of attempts
+    Reconnect { attempts: u32, source: Box<Error> },
+    /// Unable to read or parse a configuration
+    Config(String),
+    /// Unable to get user information or to drop privileges
+    Privilege(String),
+    /// Invalid UTF-8 data
+    Utf8(Utf8Error),
+    /// String containing an interior nul byte
+    Nul(NulError),
+    /// Any other error
+    Other(String),
+}
+
+impl fmt::Display for Error {
+    ///  Implement Display trait for Error
+    ///
+    /// Arguments
+    ///
+    /// * `f` -input formatter
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "IO error: {}", error),
+            Error::Protocol { expected, got } => write!(
+                f,
+                "Read number mismatched, expected {:#04x} got {:#04x}",
+                expected, got
+            ),
+            Error::InvalidKind(kind) => write!(f, "Invalid msg type {:#02x}", kind),
+            Error::PayloadTooLarge { size, max } => write!(
+                f,
+                "Payload of {} bytes is above the limit of {} bytes",
+                size, max
+            ),
+            Error::ChannelRefused(msg) => match msg.error_text() {
+                Some(text) => write!(f, "Channel is not created: {}", text),
+                None => write!(
+                    f,
+                    "Channel is not created. Tunnel service responds with msg of type {}",
+                    msg.kind
+                ),
+            },
+            Error::Closed => write!(f, "Tunnel socket is closed by peer"),
+            Error::Unresponsive(timeout) => {
+                write!(f, "No traffic from the tunnel service for {:?}", timeout)
+            }
+            Error::TopicDropped => write!(f, "Topic has been dropped"),
+            Error::Rpc(msg) => write!(f, "RPC error: {}", msg),
+            Error::Timeout => write!(f, "Operation timed out"),
+            Error::Remote(msg) => write!(f, "Remote error: {}", msg),
+            Error::Serde(msg) => write!(f, "Serialization error: {}", msg),
+            Error::Compression(msg) => write!(f, "Compression error: {}", msg),
+            Error::NotConnected => write!(f, "Topic is not connected to the tunnel"),
+            Error::Congested => write!(f, "Outbound queue is full"),
+            Error::NotSubscribed(client_id) => {
+                write!(f, "Client {} is not subscribed to the channel", client_id)
+            }
+            Error::Reconnect { attempts, source } => write!(
+                f,
+                "Unable to reconnect after {} attempts: {}",
+                attempts, source
+            ),
+            Error::Config(msg) => write!(f, "Configuration error: {}", msg),
+            Error::Privilege(msg) => write!(f, "Privilege error: {}", msg),
+            Error::Utf8(error) => write!(f, "Invalid UTF-8 data: {}", error),
+            Error::Nul(error) => write!(f, "Invalid C string: {}", error),
+            Error::Other(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            Error::Reconnect { source, .. } => Some(source.as_ref()),
+            Error::Utf8(error) => Some(error),
+            Error::Nul(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<Utf8Error> for Error {
+    fn from(error: Utf8Error) -> Self {
+        Error::Utf8(error)
+    }
+}
+
+impl From<NulError> for Error {
+    fn from(error: NulError) -> Self {
+        Error::Nul(error)
+    }
+}
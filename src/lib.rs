@@ -1,4 +1,7 @@
 #[cfg(test)]
 mod test;
+pub mod error;
 pub mod tunnel;
 pub mod utils;
+
+pub use error::{Error, Result};
@@ -2,30 +2,82 @@
 //!
 //! **Author**: "Dany LE"
 //!
-use latpr::tunnel::{CallbackEvent, IOInterest, Msg, MsgKind, Topic};
+use latpr::tunnel::{
+    Bytes, IOEvent, IOInterest, Msg, ReconnectPolicy, ShutdownHandle, Topic, TopicHandler,
+};
 use latpr::utils::*;
 use latpr::utils::{LogLevel, LOG};
 use latpr::{ERROR, EXIT, INFO, WARN};
-use std::collections::HashMap;
 use std::env;
 use std::fs;
 use std::os::unix::fs::PermissionsExt;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::os::unix::net::UnixDatagram;
 use std::panic;
+use std::sync::OnceLock;
 use std::vec::Vec;
 
+/// Shutdown handle of the topic, used by the signal handler
+static SHUTDOWN: OnceLock<ShutdownHandle> = OnceLock::new();
+
 /// Callback: clean up function
 ///
-/// This function remove the unix socket file if
-/// exist before quiting the program
+/// This function stops the topic loop which closes the
+/// channel before quiting the program
 ///
 /// # Arguments
 ///
 /// * `n` - system exit code
 fn clean_up(n: i32) {
-    if n != 0 {
-        panic!("{}", format!("pecho is terminated by system signal: {}", n));
+    match SHUTDOWN.get() {
+        Some(handle) => handle.shutdown(),
+        None if n != 0 => {
+            panic!("{}", format!("pecho is terminated by system signal: {}", n));
+        }
+        None => {}
+    }
+}
+
+/// Echo handler: forward the datagrams received on the
+/// local socket to all the subscribers of the channel
+struct Echo {
+    socket: UnixDatagram,
+}
+
+impl TopicHandler for Echo {
+    fn on_subscribe(&mut self, topic: &mut Topic, client_id: u16) -> latpr::Result<()> {
+        INFO!("Client {} subscribe to channel {}", client_id, topic.name);
+        Ok(())
+    }
+
+    fn on_unsubscribe(&mut self, topic: &mut Topic, client_id: u16) -> latpr::Result<()> {
+        INFO!("Client {} unsubscribe to channel {}", client_id, topic.name);
+        Ok(())
+    }
+
+    fn on_message(&mut self, _topic: &mut Topic, msg: &Msg) -> latpr::Result<()> {
+        WARN!(
+            "Recive mesage kind {} from client {}",
+            msg.kind,
+            msg.client_id
+        );
+        Ok(())
+    }
+
+    fn on_io(&mut self, topic: &mut Topic, _fd: RawFd, event: &IOEvent) -> latpr::Result<()> {
+        if !event.is_readable() {
+            return Ok(());
+        }
+        let mut buf = [0; 2048];
+        let (count, _) = self.socket.recv_from(&mut buf)?;
+        if topic.is_congested() {
+            WARN!("Tunnel is congested, drop {} bytes of data", count);
+            return Ok(());
+        }
+        if let Err(error) = topic.broadcast(Bytes::copy_from_slice(&buf[0..count])) {
+            WARN!("Unable to send data to subscribers: {}", error);
+        }
+        Ok(())
     }
 }
 
@@ -49,69 +101,15 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let _ = fs::remove_file(&args[3]);
     let socket = UnixDatagram::bind(&args[3])?;
     fs::set_permissions(&args[3], fs::Permissions::from_mode(0o777))?;
-    let mut clients = HashMap::<u16, u16>::new();
-    let mut msg_handle = |evt: &CallbackEvent, topic: &mut Topic| {
-        if let Some(msg) = evt.msg {
-            match msg.kind {
-                MsgKind::ChannelSubscribe => {
-                    INFO!("Client {} subscribe to channel {}", msg.client_id, &args[2]);
-                    let _ = clients.insert(msg.client_id, msg.client_id);
-                }
-                MsgKind::ChannelUnsubscribe => {
-                    INFO!(
-                        "Client {} unsubscribe to channel {}",
-                        msg.client_id,
-                        &args[2]
-                    );
-                    if let None = clients.remove(&msg.client_id) {
-                        WARN!("Client {} is not in the client list", msg.client_id);
-                    }
-                }
-                MsgKind::ChannelUnsubscribeAll => {
-                    for (key, _) in clients.iter() {
-                        let msg = Msg::create(MsgKind::ChannelUnsubscribe, 0, *key, Vec::new());
-                        topic.write(&msg)?;
-                    }
-                }
-                _ => {
-                    WARN!(
-                        "Recive mesage kind {} from client {}",
-                        msg.kind,
-                        msg.client_id
-                    );
-                }
-            };
-        }
-        let event = match evt.event {
-            None => return Ok(()),
-            Some(e) => e,
-        };
-        let _ = match evt.fd {
-            None => return Ok(()),
-            Some(d) => d,
-        };
-        if event.is_readable() {
-            let mut buf = [0; 2048];
-            let (count, _) = socket.recv_from(&mut buf)?;
-            for (key, _) in clients.iter() {
-                let msg = Msg::create(MsgKind::ChannelData, 0, *key, (&buf[0..count]).to_vec());
-                topic.write(&msg)?;
-            }
-        }
-        Ok(())
-    };
-    {
-        let mut topic = Topic::create(&args[2], &args[1]);
-        let mut running = true;
-        topic.on_message(&mut msg_handle);
-        topic.register_io(socket.as_raw_fd(), IOInterest::READABLE)?;
-        topic.open()?;
-        while running {
-            if let Err(error) = topic.step() {
-                ERROR!("Error step: {}", error);
-                running = false;
-            }
-        }
+    let socket_fd = socket.as_raw_fd();
+    let mut topic = Topic::create(&args[2], &args[1]);
+    topic.set_handler(Echo { socket });
+    topic.set_reconnect(ReconnectPolicy::default());
+    topic.register_io(socket_fd, IOInterest::READABLE)?;
+    let _ = SHUTDOWN.set(topic.shutdown_handle()?);
+    topic.open()?;
+    if let Err(error) = topic.run() {
+        ERROR!("Error step: {}", error);
     }
     Ok(())
 }
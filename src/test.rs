@@ -1 +1,1829 @@
+use crate::error::Error;
+use crate::tunnel::codec::{self, Decoder, HEADER_SIZE, TRAILER_SIZE};
+use crate::tunnel::ctrl;
+use crate::tunnel::fragment::{FragmentHeader, FRAGMENT_HEADER_SIZE};
+use crate::tunnel::rpc::{Envelope, EnvelopeKind};
+use crate::tunnel::{
+    handler, Bytes, CallbackEvent, CtrlMsg, CtrlOp, FragmentPolicy, HeartbeatPolicy, IOEvent,
+    IOInterest, Msg, MsgKind, Reactor, ReconnectPolicy, Rpc, Schedule, Signal, TimerId, Topic,
+    TopicHandler,
+};
+use std::io::{Cursor, Write};
+use std::ops::Deref;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 
+static SOCK_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Path of a test socket, the socket file is removed on drop
+struct SocketPath(String);
+
+impl Drop for SocketPath {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+impl Deref for SocketPath {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for SocketPath {
+    fn as_ref(&self) -> &Path {
+        Path::new(&self.0)
+    }
+}
+
+impl From<&SocketPath> for String {
+    fn from(path: &SocketPath) -> Self {
+        path.0.clone()
+    }
+}
+
+/// Get a unique socket path for a test
+fn socket_path() -> SocketPath {
+    let path = format!(
+        "/tmp/latpr-test-{}-{}.sock",
+        std::process::id(),
+        SOCK_ID.fetch_add(1, Ordering::SeqCst)
+    );
+    SocketPath(path)
+}
+
+/// Start a fake tunnel server accepting one topic
+///
+/// The server confirms the channel opening then hands
+/// the connection over to `f`
+fn fake_tunnel<F>(f: F) -> (SocketPath, thread::JoinHandle<()>)
+where
+    F: FnOnce(UnixStream) + Send + 'static,
+{
+    let path = socket_path();
+    let listener = UnixListener::bind(&path).unwrap();
+    let handle = thread::spawn(move || {
+        let stream = accept_topic(&listener);
+        f(stream);
+    });
+    (path, handle)
+}
+
+/// Accept a topic connection and confirm the channel opening
+fn accept_topic(listener: &UnixListener) -> UnixStream {
+    let (mut stream, _) = listener.accept().unwrap();
+    let open = codec::read_msg(&mut stream).unwrap();
+    assert!(matches!(open.kind, MsgKind::ChannelOpen));
+    let ok = Msg::create(MsgKind::ChannelOk, 0, 0, Vec::new());
+    codec::write_msg(&mut stream, &ok).unwrap();
+    stream
+}
+
+/// Consume the messages sent by the topic until it closes the channel
+fn wait_close(stream: &mut UnixStream) -> Vec<Msg> {
+    let mut msgs = Vec::new();
+    while let Ok(msg) = codec::read_msg(stream) {
+        let closed = matches!(msg.kind, MsgKind::ChannelClose);
+        msgs.push(msg);
+        if closed {
+            break;
+        }
+    }
+    msgs
+}
+
+fn sample_msg() -> Msg {
+    Msg::create(MsgKind::ChannelData, 0x12, 0x34, b"hello".to_vec())
+}
+
+#[test]
+fn codec_encode_layout() {
+    let mut buf = Vec::new();
+    codec::encode(&sample_msg(), &mut buf).unwrap();
+    assert_eq!(buf.len(), HEADER_SIZE + 5 + TRAILER_SIZE);
+    assert_eq!(
+        buf,
+        [
+            0x41, 0x4e, 0x06, 0x00, 0x12, 0x00, 0x34, 0x00, 0x00, 0x00, 0x05, b'h', b'e', b'l',
+            b'l', b'o', 0x54, 0x44
+        ]
+    );
+}
+
+#[test]
+fn codec_read_write_roundtrip() {
+    let mut buf = Vec::new();
+    codec::write_msg(&mut buf, &sample_msg()).unwrap();
+    let msg = codec::read_msg(&mut Cursor::new(buf)).unwrap();
+    assert!(matches!(msg.kind, MsgKind::ChannelData));
+    assert_eq!(msg.channel_id, 0x12);
+    assert_eq!(msg.client_id, 0x34);
+    assert_eq!(msg.size, 5);
+    assert_eq!(&msg.data[..], b"hello");
+}
+
+#[test]
+fn codec_encode_payload_size() {
+    // the size field follows the payload, not a stale `size`
+    let mut msg = sample_msg();
+    msg.data = Bytes::from_static(b"hello world");
+    let mut buf = Vec::new();
+    codec::encode(&msg, &mut buf).unwrap();
+    assert_eq!(
+        &codec::encode_header(&msg).unwrap()[..],
+        &buf[..HEADER_SIZE]
+    );
+    codec::write_msg(&mut buf, &msg).unwrap();
+    let mut reader = Cursor::new(buf);
+    for _ in 0..2 {
+        let decoded = codec::read_msg(&mut reader).unwrap();
+        assert_eq!(decoded.size, 11);
+        assert_eq!(&decoded.data[..], b"hello world");
+    }
+}
+
+#[test]
+fn codec_decoder_partial_frames() {
+    let mut buf = Vec::new();
+    codec::encode(&sample_msg(), &mut buf).unwrap();
+    codec::encode(&Msg::create(MsgKind::ChannelOk, 0, 0, Vec::new()), &mut buf).unwrap();
+    let mut decoder = Decoder::new();
+    let mut msgs = Vec::new();
+    for chunk in buf.chunks(3) {
+        decoder.feed(chunk);
+        while let Some(msg) = decoder.decode().unwrap() {
+            msgs.push(msg);
+        }
+    }
+    assert_eq!(msgs.len(), 2);
+    assert_eq!(&msgs[0].data[..], b"hello");
+    assert!(matches!(msgs[1].kind, MsgKind::ChannelOk));
+    assert_eq!(decoder.pending(), 0);
+}
+
+#[test]
+fn codec_bad_magic() {
+    let mut buf = Vec::new();
+    codec::encode(&sample_msg(), &mut buf).unwrap();
+    buf[0] = 0;
+    assert!(matches!(
+        codec::decode(&buf),
+        Err(Error::Protocol {
+            expected: 0x414e,
+            got: 0x004e
+        })
+    ));
+    let len = buf.len();
+    buf[0] = 0x41;
+    buf[len - 1] = 0;
+    assert!(matches!(
+        codec::decode(&buf),
+        Err(Error::Protocol {
+            expected: 0x5444,
+            got: 0x5400
+        })
+    ));
+}
+
+#[test]
+fn error_kinds() {
+    assert!(matches!(
+        crate::utils::read_config("/nonexistent/latpr.conf"),
+        Err(Error::Config(_))
+    ));
+    assert!(matches!(
+        crate::utils::string_from_u8(&[0xff, 0xfe]),
+        Err(Error::Utf8(_))
+    ));
+    assert!(matches!(
+        crate::utils::privdrop(None, None),
+        Err(Error::Privilege(_))
+    ));
+}
+
+#[test]
+fn topic_step_incremental_read() {
+    let (path, server) = fake_tunnel(|mut stream| {
+        let mut buf = Vec::new();
+        codec::encode(&sample_msg(), &mut buf).unwrap();
+        // first frame is written in two parts
+        stream.write_all(&buf[..7]).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        stream.write_all(&buf[7..]).unwrap();
+        // then two frames at once
+        let mut buf = Vec::new();
+        codec::encode(&sample_msg(), &mut buf).unwrap();
+        codec::encode(&sample_msg(), &mut buf).unwrap();
+        stream.write_all(&buf).unwrap();
+        wait_close(&mut stream);
+    });
+    let (tx, rx) = mpsc::channel();
+    let handle = move |evt: &CallbackEvent, _: &mut Topic| {
+        if let Some(msg) = evt.msg {
+            if let MsgKind::ChannelData = msg.kind {
+                tx.send(msg.data.to_vec()).unwrap();
+            }
+        }
+        Ok(())
+    };
+    {
+        let mut topic = Topic::create("test", &path);
+        topic.on_message(handle);
+        topic.set_step_to(Duration::from_millis(10));
+        topic.open().unwrap();
+        for _ in 0..20 {
+            topic.step().unwrap();
+        }
+    }
+    server.join().unwrap();
+    let msgs: Vec<Vec<u8>> = rx.try_iter().collect();
+    assert_eq!(msgs.len(), 3);
+    assert!(msgs.iter().all(|m| m == b"hello"));
+}
+
+#[test]
+fn topic_dispatch_before_decoding_error() {
+    let (path, server) = fake_tunnel(|mut stream| {
+        // a valid frame followed by garbage in the same chunk
+        let mut buf = Vec::new();
+        codec::encode(&sample_msg(), &mut buf).unwrap();
+        buf.extend_from_slice(&[0xFF; 32]);
+        stream.write_all(&buf).unwrap();
+        wait_close(&mut stream);
+    });
+    let (tx, rx) = mpsc::channel();
+    let mut topic = Topic::create("test", &path);
+    topic.on_message(move |evt: &CallbackEvent, _: &mut Topic| {
+        if let Some(msg) = evt.msg {
+            tx.send(msg.data.to_vec()).unwrap();
+        }
+        Ok(())
+    });
+    topic.open().unwrap();
+    let error = loop {
+        if let Err(error) = topic.step() {
+            break error;
+        }
+    };
+    assert!(matches!(error, Error::Protocol { .. }));
+    assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![b"hello".to_vec()]);
+    drop(topic);
+    server.join().unwrap();
+}
+
+#[test]
+fn topic_outbound_backpressure() {
+    let (go_tx, go_rx) = mpsc::channel::<()>();
+    let (path, server) = fake_tunnel(move |mut stream| {
+        go_rx.recv().unwrap();
+        wait_close(&mut stream);
+    });
+    let (tx, rx) = mpsc::channel();
+    let handle = move |evt: &CallbackEvent, _: &mut Topic| {
+        if let Some(signal) = evt.signal {
+            tx.send(signal).unwrap();
+        }
+        Ok(())
+    };
+    {
+        let mut topic = Topic::create("test", &path);
+        topic.on_message(handle);
+        topic.set_step_to(Duration::from_millis(10));
+        topic.set_high_water_mark(1 << 16);
+        topic.open().unwrap();
+        let data = Msg::create(MsgKind::ChannelData, 0, 1, vec![0; 1 << 16]);
+        let mut rejected = false;
+        for _ in 0..1024 {
+            if topic.write(&data).is_err() {
+                rejected = true;
+                break;
+            }
+        }
+        assert!(rejected);
+        assert!(topic.is_congested());
+        assert!(topic.pending_bytes() >= 1 << 16);
+        go_tx.send(()).unwrap();
+        for _ in 0..100 {
+            topic.step().unwrap();
+            if !topic.is_congested() {
+                break;
+            }
+        }
+        assert!(!topic.is_congested());
+        assert_eq!(topic.pending_bytes(), 0);
+        topic.write(&data).unwrap();
+    }
+    server.join().unwrap();
+    assert_eq!(
+        rx.try_iter().collect::<Vec<_>>(),
+        vec![Signal::Drained, Signal::Closing]
+    );
+}
+
+#[test]
+fn topic_reconnect_after_tunnel_restart() {
+    let path = socket_path();
+    let listener = UnixListener::bind(&path).unwrap();
+    let server_path = String::from(&path);
+    let server = thread::spawn(move || {
+        // the first tunnel dies right after the channel is opened
+        drop(accept_topic(&listener));
+        drop(listener);
+        let _ = std::fs::remove_file(&server_path);
+        thread::sleep(Duration::from_millis(50));
+        let listener = UnixListener::bind(&server_path).unwrap();
+        let mut stream = accept_topic(&listener);
+        wait_close(&mut stream);
+    });
+    let (tx, rx) = mpsc::channel();
+    let handle = move |evt: &CallbackEvent, _: &mut Topic| {
+        if let Some(signal) = evt.signal {
+            tx.send(signal).unwrap();
+        }
+        Ok(())
+    };
+    {
+        let mut topic = Topic::create("test", &path);
+        topic.on_message(handle);
+        topic.set_step_to(Duration::from_millis(10));
+        topic.set_reconnect(ReconnectPolicy {
+            initial_delay: Duration::from_millis(5),
+            max_delay: Duration::from_millis(20),
+            max_attempts: Some(50),
+            ..ReconnectPolicy::default()
+        });
+        topic.open().unwrap();
+        for _ in 0..200 {
+            topic.step().unwrap();
+            if rx.try_recv() == Ok(Signal::Reconnected) {
+                break;
+            }
+        }
+        assert!(topic.is_connected());
+    }
+    server.join().unwrap();
+}
+
+#[test]
+fn topic_heartbeat_detects_dead_tunnel() {
+    let path = socket_path();
+    let listener = UnixListener::bind(&path).unwrap();
+    let server = thread::spawn(move || {
+        // the first tunnel answers one ping then hangs without closing
+        let mut stream = accept_topic(&listener);
+        let mut pings = 0;
+        while let Ok(msg) = codec::read_msg(&mut stream) {
+            assert_eq!(msg.kind, MsgKind::ChannelCtrl);
+            assert_eq!(msg.client_id, 0);
+            assert_eq!(CtrlMsg::decode(&msg.data).unwrap().op, CtrlOp::Ping);
+            pings += 1;
+            if pings == 1 {
+                let pong = CtrlMsg::create(CtrlOp::Pong, Bytes::new());
+                let pong = Msg::create(MsgKind::ChannelCtrl, 0, 0, pong.encode());
+                codec::write_msg(&mut stream, &pong).unwrap();
+            }
+        }
+        let mut stream = accept_topic(&listener);
+        wait_close(&mut stream);
+        pings
+    });
+    let (tx, rx) = mpsc::channel();
+    let handle = move |evt: &CallbackEvent, _: &mut Topic| {
+        if let Some(signal) = evt.signal {
+            tx.send(format!("{:?}", signal)).unwrap();
+        }
+        if let Some(msg) = evt.msg {
+            tx.send(format!("{}", msg.kind)).unwrap();
+        }
+        Ok(())
+    };
+    {
+        let mut topic = Topic::create("test", &path);
+        topic.on_message(handle);
+        topic.set_heartbeat(HeartbeatPolicy {
+            interval: Duration::from_millis(20),
+            timeout: Duration::from_millis(150),
+        });
+        topic.set_reconnect(ReconnectPolicy {
+            initial_delay: Duration::from_millis(5),
+            max_delay: Duration::from_millis(20),
+            max_attempts: Some(50),
+            ..ReconnectPolicy::default()
+        });
+        topic.open().unwrap();
+        let started = Instant::now();
+        let mut events = Vec::new();
+        while !events.iter().any(|evt| evt == "Reconnected") {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            topic.step().unwrap();
+            events.extend(rx.try_iter());
+        }
+        // the pong is consumed by the topic
+        assert_eq!(events, ["Disconnected", "Reconnected"]);
+        assert!(started.elapsed() >= Duration::from_millis(150));
+        assert!(topic.is_connected());
+    }
+    assert!(server.join().unwrap() > 1);
+}
+
+#[test]
+fn topic_heartbeat_without_reconnect() {
+    let (done, wait) = mpsc::channel::<()>();
+    let (path, server) = fake_tunnel(move |stream| {
+        // hung tunnel, nothing is read from the socket
+        let _ = wait.recv();
+        drop(stream);
+    });
+    let mut topic = Topic::create("test", &path);
+    topic.set_heartbeat(HeartbeatPolicy {
+        interval: Duration::from_millis(10),
+        timeout: Duration::from_millis(100),
+    });
+    topic.set_high_water_mark(usize::MAX);
+    topic.open().unwrap();
+    // fill the socket buffer
+    while topic.pending_bytes() < 1 << 20 {
+        let msg = Msg::create(MsgKind::ChannelData, 0, 1, vec![0; 64 * 1024]);
+        topic.write(&msg).unwrap();
+    }
+    let error = loop {
+        if let Err(error) = topic.step() {
+            break error;
+        }
+    };
+    assert!(matches!(error, Error::Unresponsive(_)));
+    assert!(!topic.is_connected());
+    let started = Instant::now();
+    drop(topic);
+    assert!(started.elapsed() < Duration::from_millis(500));
+    done.send(()).unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn topic_close_stalled_tunnel() {
+    let (done, wait) = mpsc::channel::<()>();
+    let (path, server) = fake_tunnel(move |stream| {
+        let _ = wait.recv();
+        drop(stream);
+    });
+    let mut topic = Topic::create("test", &path);
+    topic.set_high_water_mark(usize::MAX);
+    topic.open().unwrap();
+    while topic.pending_bytes() < 1 << 20 {
+        let msg = Msg::create(MsgKind::ChannelData, 0, 1, vec![0; 64 * 1024]);
+        topic.write(&msg).unwrap();
+    }
+    let started = Instant::now();
+    topic.close().unwrap();
+    // the pending messages are dropped after the flush timeout
+    assert!(started.elapsed() < Duration::from_secs(3));
+    done.send(()).unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn topic_reconnect_to_hung_tunnel() {
+    let path = socket_path();
+    let listener = UnixListener::bind(&path).unwrap();
+    let (done, wait) = mpsc::channel::<()>();
+    let server = thread::spawn(move || {
+        // the tunnel drops the first connection then stops accepting,
+        // the new connections wait in the listen backlog
+        drop(accept_topic(&listener));
+        let _ = wait.recv();
+    });
+    let mut topic = Topic::create("test", &path);
+    topic.set_reconnect(ReconnectPolicy {
+        initial_delay: Duration::from_millis(5),
+        max_delay: Duration::from_millis(20),
+        max_attempts: Some(3),
+        handshake_timeout: Duration::from_millis(50),
+    });
+    topic.open().unwrap();
+    let started = Instant::now();
+    let error = loop {
+        assert!(started.elapsed() < Duration::from_secs(5));
+        if let Err(error) = topic.step() {
+            break error;
+        }
+    };
+    match error {
+        Error::Reconnect { attempts, source } => {
+            assert_eq!(attempts, 3);
+            assert!(matches!(*source, Error::Timeout));
+        }
+        error => panic!("unexpected error {}", error),
+    }
+    assert!(!topic.is_connected());
+    drop(topic);
+    done.send(()).unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn topic_heartbeat_is_not_activity() {
+    let (pings_tx, pings) = mpsc::channel();
+    let (path, server) = fake_tunnel(move |mut stream| {
+        // answer every ping until the channel is closed
+        let mut pings = 0;
+        while let Ok(msg) = codec::read_msg(&mut stream) {
+            if msg.kind != MsgKind::ChannelCtrl {
+                break;
+            }
+            pings += 1;
+            let pong = CtrlMsg::create(CtrlOp::Pong, Bytes::new());
+            let pong = Msg::create(MsgKind::ChannelCtrl, 0, 0, pong.encode());
+            codec::write_msg(&mut stream, &pong).unwrap();
+        }
+        pings_tx.send(pings).unwrap();
+    });
+    let (tx, rx) = mpsc::channel();
+    let mut topic = Topic::create("test", &path);
+    topic.set_handler(Recorder(tx));
+    topic.set_step_to(Duration::from_millis(50));
+    topic.set_heartbeat(HeartbeatPolicy {
+        interval: Duration::from_millis(10),
+        timeout: Duration::from_secs(5),
+    });
+    topic.open().unwrap();
+    let started = Instant::now();
+    // the pongs of the tunnel do not delay the idle event
+    while !rx.try_iter().any(|call| call == "idle") {
+        assert!(started.elapsed() < Duration::from_secs(1));
+        topic.step().unwrap();
+    }
+    drop(topic);
+    server.join().unwrap();
+    assert!(pings.recv().unwrap() >= 3);
+}
+
+#[test]
+fn reconnect_policy_backoff() {
+    let policy = ReconnectPolicy {
+        initial_delay: Duration::from_millis(100),
+        max_delay: Duration::from_secs(1),
+        ..ReconnectPolicy::default()
+    };
+    assert_eq!(policy.delay(0), Duration::from_millis(100));
+    assert_eq!(policy.delay(2), Duration::from_millis(400));
+    assert_eq!(policy.delay(4), Duration::from_secs(1));
+    assert_eq!(policy.delay(64), Duration::from_secs(1));
+}
+
+#[test]
+fn topic_open_refused() {
+    let path = socket_path();
+    let listener = UnixListener::bind(&path).unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let _ = codec::read_msg(&mut stream).unwrap();
+        let refused = Msg::create(MsgKind::ChannelError, 0, 0, b"Channel exists".to_vec());
+        codec::write_msg(&mut stream, &refused).unwrap();
+        wait_close(&mut stream);
+    });
+    {
+        let mut topic = Topic::create("test", &path);
+        match topic.open() {
+            Err(Error::ChannelRefused(msg)) => {
+                assert_eq!(msg.error_text().as_deref(), Some("Channel exists"));
+            }
+            _ => panic!("channel opening should be refused"),
+        }
+        assert!(!topic.is_connected());
+    }
+    server.join().unwrap();
+}
+
+#[test]
+fn msg_kind_conversion() {
+    for value in 0..=u8::MAX {
+        match MsgKind::try_from(value) {
+            Ok(kind) => assert_eq!(u8::from(kind), value),
+            Err(Error::InvalidKind(kind)) => {
+                assert_eq!(kind, value);
+                assert_eq!(MsgKind::from_u8(value), MsgKind::Unknown(value));
+                assert_eq!(u8::from(MsgKind::from_u8(value)), value);
+            }
+            Err(_) => panic!("unexpected error"),
+        }
+    }
+    assert_eq!(
+        MsgKind::try_from(0xA).unwrap(),
+        MsgKind::ChannelUnsubscribeAll
+    );
+    assert!(MsgKind::try_from(0x8).is_err());
+    assert_eq!(MsgKind::ChannelData.to_string(), "ChannelData");
+    assert_eq!(MsgKind::Unknown(0x42).to_string(), "Unknown(0x42)");
+}
+
+#[test]
+fn codec_decoder_lenient() {
+    let mut buf = Vec::new();
+    codec::encode(
+        &Msg::create(MsgKind::ChannelUnsubscribeAll, 0, 0, Vec::new()),
+        &mut buf,
+    )
+    .unwrap();
+    codec::encode(
+        &Msg::create(MsgKind::Unknown(0x9), 0, 0, Vec::new()),
+        &mut buf,
+    )
+    .unwrap();
+    let mut decoder = Decoder::new();
+    decoder.feed(&buf);
+    assert_eq!(
+        decoder.decode().unwrap().unwrap().kind,
+        MsgKind::ChannelUnsubscribeAll
+    );
+    assert!(matches!(decoder.decode(), Err(Error::InvalidKind(0x9))));
+    decoder.set_lenient(true);
+    assert_eq!(
+        decoder.decode().unwrap().unwrap().kind,
+        MsgKind::Unknown(0x9)
+    );
+}
+
+#[test]
+fn codec_decoder_resync() {
+    let mut frame = Vec::new();
+    codec::encode(&sample_msg(), &mut frame).unwrap();
+    let mut corrupted = frame.clone();
+    let len = corrupted.len();
+    corrupted[len - 1] = 0;
+    let mut buf = vec![0x41, 0x01, 0x02];
+    buf.extend_from_slice(&corrupted);
+    buf.extend_from_slice(&frame);
+    buf.push(0x41);
+    let mut decoder = Decoder::new();
+    decoder.feed(&buf);
+    assert!(decoder.decode().is_err());
+    decoder.set_resync(true);
+    let msg = decoder.decode().unwrap().unwrap();
+    assert_eq!(&msg.data[..], b"hello");
+    assert_eq!(decoder.dropped_bytes(), (3 + corrupted.len()) as u64);
+    assert!(decoder.decode().unwrap().is_none());
+    // the trailing half magic is kept for the next frame
+    assert_eq!(decoder.pending(), 1);
+    decoder.feed(&frame[1..]);
+    assert_eq!(&decoder.decode().unwrap().unwrap().data[..], b"hello");
+    assert_eq!(decoder.dropped_bytes(), (3 + corrupted.len()) as u64);
+}
+
+#[test]
+fn codec_max_payload() {
+    let mut big = Vec::new();
+    codec::encode(
+        &Msg::create(MsgKind::ChannelData, 0, 1, vec![0; 100]),
+        &mut big,
+    )
+    .unwrap();
+    // the size field alone is enough to reject the frame
+    let mut header = big[..HEADER_SIZE].to_vec();
+    header[7..11].copy_from_slice(&u32::MAX.to_be_bytes());
+    assert!(matches!(
+        codec::read_msg(&mut Cursor::new(header)),
+        Err(Error::PayloadTooLarge {
+            size: 0xFFFF_FFFF,
+            ..
+        })
+    ));
+    assert!(matches!(
+        codec::read_msg_max(&mut Cursor::new(big.clone()), 99),
+        Err(Error::PayloadTooLarge { size: 100, max: 99 })
+    ));
+
+    let mut decoder = Decoder::new();
+    decoder.set_max_payload(64);
+    decoder.feed(&big);
+    assert!(matches!(
+        decoder.decode(),
+        Err(Error::PayloadTooLarge { size: 100, max: 64 })
+    ));
+
+    let mut frame = Vec::new();
+    codec::encode(&sample_msg(), &mut frame).unwrap();
+    let mut decoder = Decoder::new();
+    decoder.set_max_payload(64);
+    decoder.set_discard_oversized(true);
+    let mut chunks = big.chunks(16);
+    decoder.feed(chunks.next().unwrap());
+    assert!(decoder.decode().unwrap().is_none());
+    // the rest of the oversized frame is skipped as it arrives
+    for chunk in chunks {
+        decoder.feed(chunk);
+        assert_eq!(decoder.pending(), 0);
+    }
+    decoder.feed(&frame);
+    assert_eq!(&decoder.decode().unwrap().unwrap().data[..], b"hello");
+    assert_eq!(decoder.discarded_frames(), 1);
+    assert_eq!(decoder.pending(), 0);
+}
+
+#[test]
+fn topic_discard_oversized() {
+    let (received_tx, received) = mpsc::channel();
+    let (path, server) = fake_tunnel(move |mut stream| {
+        let big = Msg::create(MsgKind::ChannelData, 0, 1, vec![0; 8 << 20]);
+        codec::write_msg(&mut stream, &big).unwrap();
+        codec::write_msg(&mut stream, &sample_msg()).unwrap();
+        // wait until the discarding is disabled, then the frame is
+        // rejected from its header
+        received.recv().unwrap();
+        let mut header = [0; HEADER_SIZE];
+        header.copy_from_slice(&codec::encode_header(&big).unwrap());
+        stream.write_all(&header).unwrap();
+        wait_close(&mut stream);
+    });
+    let (tx, rx) = mpsc::channel();
+    let mut topic = Topic::create("test", &path);
+    topic.set_max_payload(1024);
+    topic.set_discard_oversized(true);
+    topic.on_message(move |evt: &CallbackEvent, topic: &mut Topic| {
+        if let Some(signal) = evt.signal {
+            tx.send(format!("{:?}", signal)).unwrap();
+        }
+        if let Some(msg) = evt.msg {
+            tx.send(String::from_utf8_lossy(&msg.data).into_owned())
+                .unwrap();
+            topic.set_discard_oversized(false);
+            received_tx.send(()).unwrap();
+        }
+        Ok(())
+    });
+    topic.open().unwrap();
+    let error = loop {
+        if let Err(error) = topic.step() {
+            break error;
+        }
+    };
+    assert!(matches!(
+        error,
+        Error::PayloadTooLarge {
+            size: 0x800000,
+            max: 1024
+        }
+    ));
+    assert_eq!(
+        rx.try_iter().collect::<Vec<_>>(),
+        ["Discarded(1)", "hello", "Disconnected"]
+    );
+    drop(topic);
+    server.join().unwrap();
+}
+
+#[test]
+fn codec_decoder_zero_copy() {
+    let mut buf = Vec::new();
+    codec::encode(&sample_msg(), &mut buf).unwrap();
+    codec::encode(&sample_msg(), &mut buf).unwrap();
+    let mut decoder = Decoder::new();
+    decoder
+        .read_from(&mut Cursor::new(buf.clone()), buf.len())
+        .unwrap();
+    let first = decoder.decode().unwrap().unwrap();
+    let second = decoder.decode().unwrap().unwrap();
+    // both payloads are slices of the same decoder buffer
+    let frame_size = (HEADER_SIZE + 5 + TRAILER_SIZE) as isize;
+    assert_eq!(
+        unsafe { second.data.as_ptr().offset_from(first.data.as_ptr()) },
+        frame_size
+    );
+}
+
+#[test]
+fn topic_write_shared_payload() {
+    let (path, server) = fake_tunnel(|mut stream| {
+        let msgs = wait_close(&mut stream);
+        let data: Vec<&Msg> = msgs
+            .iter()
+            .filter(|msg| msg.kind == MsgKind::ChannelData)
+            .collect();
+        assert_eq!(data.len(), 16);
+        for (i, msg) in data.iter().enumerate() {
+            assert_eq!(msg.client_id, i as u16);
+            assert_eq!(&msg.data[..], &[7; 1000][..]);
+        }
+    });
+    {
+        let mut topic = Topic::create("test", &path);
+        topic.open().unwrap();
+        let payload = Bytes::from(vec![7; 1000]);
+        for client in 0..16 {
+            let msg = Msg::create(MsgKind::ChannelData, 0, client, payload.clone());
+            assert_eq!(msg.data.as_ptr(), payload.as_ptr());
+            topic.write(&msg).unwrap();
+        }
+    }
+    server.join().unwrap();
+}
+
+#[test]
+fn topic_moved_to_thread() {
+    let (path, server) = fake_tunnel(|mut stream| {
+        codec::write_msg(&mut stream, &sample_msg()).unwrap();
+        wait_close(&mut stream);
+    });
+    let (tx, rx) = mpsc::channel();
+    let mut topic = Topic::create(String::from("test"), String::from(&path));
+    topic.on_message(move |evt: &CallbackEvent, _: &mut Topic| {
+        if let Some(msg) = evt.msg.filter(|msg| msg.kind == MsgKind::ChannelData) {
+            tx.send(msg.data.to_vec()).unwrap();
+        }
+        Ok(())
+    });
+    topic.set_step_to(Duration::from_millis(10));
+    let worker = thread::spawn(move || {
+        topic.open().unwrap();
+        for _ in 0..10 {
+            topic.step().unwrap();
+        }
+    });
+    worker.join().unwrap();
+    server.join().unwrap();
+    assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![b"hello".to_vec()]);
+}
+
+#[test]
+fn reactor_hosts_several_topics() {
+    // each tunnel sends one message and expects it echoed back
+    let tunnel = |payload: &'static [u8]| {
+        move |mut stream: UnixStream| {
+            let msg = Msg::create(MsgKind::ChannelData, 0, 1, payload);
+            codec::write_msg(&mut stream, &msg).unwrap();
+            let msgs = wait_close(&mut stream);
+            assert!(msgs
+                .iter()
+                .any(|msg| msg.kind == MsgKind::ChannelData && &msg.data[..] == payload));
+        }
+    };
+    let (path_a, server_a) = fake_tunnel(tunnel(b"from a"));
+    let (path_b, server_b) = fake_tunnel(tunnel(b"from b"));
+    let (tx, rx) = mpsc::channel();
+    let tx_b = tx.clone();
+    let handle_a = move |evt: &CallbackEvent, topic: &mut Topic| {
+        if let Some(msg) = evt.msg.filter(|msg| msg.kind == MsgKind::ChannelData) {
+            tx.send(("a", msg.data.to_vec())).unwrap();
+            topic.write(msg)?;
+        }
+        Ok(())
+    };
+    let handle_b = move |evt: &CallbackEvent, topic: &mut Topic| {
+        if let Some(msg) = evt.msg.filter(|msg| msg.kind == MsgKind::ChannelData) {
+            tx_b.send(("b", msg.data.to_vec())).unwrap();
+            topic.write(msg)?;
+        }
+        Ok(())
+    };
+    {
+        let mut reactor = Reactor::new().unwrap();
+        reactor.set_step_to(Duration::from_millis(10));
+        // a topic opened before being added
+        let mut topic_a = Topic::create("a", &path_a);
+        topic_a.on_message(handle_a);
+        topic_a.open().unwrap();
+        let id_a = reactor.add(topic_a).unwrap();
+        // a topic opened once hosted
+        let mut topic_b = Topic::create("b", &path_b);
+        topic_b.on_message(handle_b);
+        let id_b = reactor.add(topic_b).unwrap();
+        reactor.topic_mut(id_b).unwrap().open().unwrap();
+        assert_ne!(id_a, id_b);
+        assert_eq!(reactor.len(), 2);
+        assert!(reactor.topic_mut(id_a).unwrap().step().is_err());
+        for _ in 0..20 {
+            reactor.step().unwrap();
+        }
+    }
+    server_a.join().unwrap();
+    server_b.join().unwrap();
+    let mut msgs: Vec<(&str, Vec<u8>)> = rx.try_iter().collect();
+    msgs.sort();
+    assert_eq!(
+        msgs,
+        vec![("a", b"from a".to_vec()), ("b", b"from b".to_vec())]
+    );
+}
+
+/// Handler recording the name of each called method
+struct Recorder(mpsc::Sender<String>);
+
+impl TopicHandler for Recorder {
+    fn on_subscribe(&mut self, _: &mut Topic, client_id: u16) -> crate::Result<()> {
+        self.0.send(format!("subscribe {}", client_id)).unwrap();
+        Ok(())
+    }
+
+    fn on_unsubscribe(&mut self, _: &mut Topic, client_id: u16) -> crate::Result<()> {
+        self.0.send(format!("unsubscribe {}", client_id)).unwrap();
+        Ok(())
+    }
+
+    fn on_unsubscribe_all(&mut self, _: &mut Topic) -> crate::Result<()> {
+        self.0.send(String::from("unsubscribe all")).unwrap();
+        Ok(())
+    }
+
+    fn on_data(&mut self, _: &mut Topic, client_id: u16, data: &[u8]) -> crate::Result<()> {
+        let data = String::from_utf8_lossy(data);
+        self.0.send(format!("data {} {}", client_id, data)).unwrap();
+        Ok(())
+    }
+
+    fn on_ctrl(&mut self, _: &mut Topic, client_id: u16, data: &[u8]) -> crate::Result<()> {
+        let data = String::from_utf8_lossy(data);
+        self.0.send(format!("ctrl {} {}", client_id, data)).unwrap();
+        Ok(())
+    }
+
+    fn on_io(&mut self, _: &mut Topic, _: RawFd, event: &IOEvent) -> crate::Result<()> {
+        if event.is_readable() {
+            self.0.send(String::from("io")).unwrap();
+        }
+        Ok(())
+    }
+
+    fn on_idle(&mut self, _: &mut Topic) -> crate::Result<()> {
+        self.0.send(String::from("idle")).unwrap();
+        Ok(())
+    }
+
+    fn on_timer(&mut self, _: &mut Topic, _: TimerId) -> crate::Result<()> {
+        self.0.send(String::from("timer")).unwrap();
+        Ok(())
+    }
+
+    fn on_close(&mut self, _: &mut Topic) -> crate::Result<()> {
+        self.0.send(String::from("close")).unwrap();
+        Ok(())
+    }
+}
+
+#[test]
+fn topic_handler_dispatch() {
+    let (path, server) = fake_tunnel(|mut stream| {
+        for (kind, data) in [
+            (MsgKind::ChannelSubscribe, &b""[..]),
+            (MsgKind::ChannelData, b"hello"),
+            (MsgKind::ChannelCtrl, b"ping"),
+            (MsgKind::ChannelUnsubscribe, b""),
+        ] {
+            let msg = Msg::create(kind, 0, 5, data);
+            codec::write_msg(&mut stream, &msg).unwrap();
+        }
+        wait_close(&mut stream);
+    });
+    let (tx, rx) = mpsc::channel();
+    let (local, remote) = UnixDatagram::pair().unwrap();
+    {
+        let mut topic = Topic::create("test", &path);
+        topic.set_handler(Recorder(tx));
+        topic.set_step_to(Duration::from_millis(10));
+        topic
+            .register_io(local.as_raw_fd(), IOInterest::READABLE)
+            .unwrap();
+        topic.open().unwrap();
+        remote.send(b"io").unwrap();
+        for _ in 0..10 {
+            topic.step().unwrap();
+        }
+    }
+    server.join().unwrap();
+    let calls: Vec<String> = rx.try_iter().collect();
+    assert!(calls.iter().any(|call| call == "idle"));
+    assert!(calls.iter().any(|call| call == "io"));
+    let calls: Vec<&String> = calls
+        .iter()
+        .filter(|call| *call != "idle" && *call != "io")
+        .collect();
+    assert_eq!(
+        calls,
+        [
+            "subscribe 5",
+            "data 5 hello",
+            "ctrl 5 ping",
+            "unsubscribe 5",
+            "unsubscribe all",
+            "close"
+        ]
+    );
+}
+
+#[test]
+fn topic_subscriber_registry() {
+    let (path, server) = fake_tunnel(|mut stream| {
+        for (kind, client_id) in [
+            (MsgKind::ChannelSubscribe, 1),
+            (MsgKind::ChannelSubscribe, 2),
+            (MsgKind::ChannelSubscribe, 3),
+            (MsgKind::ChannelUnsubscribe, 2),
+        ] {
+            let msg = Msg::create(kind, 0, client_id, Vec::new());
+            codec::write_msg(&mut stream, &msg).unwrap();
+        }
+        let mut msgs: Vec<(u8, u16, Vec<u8>)> = wait_close(&mut stream)
+            .into_iter()
+            .map(|msg| (u8::from(msg.kind), msg.client_id, msg.data.to_vec()))
+            .collect();
+        assert_eq!(
+            msgs.pop().map(|msg| msg.0),
+            Some(MsgKind::ChannelClose.into())
+        );
+        msgs.sort();
+        assert_eq!(
+            msgs,
+            vec![
+                (MsgKind::ChannelUnsubscribe.into(), 1, Vec::new()),
+                (MsgKind::ChannelUnsubscribe.into(), 3, Vec::new()),
+                (MsgKind::ChannelData.into(), 1, b"all".to_vec()),
+                (MsgKind::ChannelData.into(), 3, b"all".to_vec()),
+                (MsgKind::ChannelData.into(), 3, b"one".to_vec()),
+            ]
+        );
+    });
+    {
+        let mut topic = Topic::create("test", &path);
+        topic.set_step_to(Duration::from_millis(10));
+        topic.open().unwrap();
+        for _ in 0..10 {
+            topic.step().unwrap();
+        }
+        let mut subscribers: Vec<u16> = topic.subscribers().ids().collect();
+        subscribers.sort();
+        assert_eq!(subscribers, vec![1, 3]);
+        assert!(!topic.is_subscribed(2));
+        topic.broadcast(&b"all"[..]).unwrap();
+        topic.send_to(3, &b"one"[..]).unwrap();
+        assert!(matches!(
+            topic.send_to(2, &b"one"[..]),
+            Err(Error::NotSubscribed(2))
+        ));
+    }
+    server.join().unwrap();
+}
+
+/// Handler reporting the nickname and the statistics of the leaving subscribers
+struct Lifecycle {
+    results: mpsc::Sender<(u16, String, u64, u64)>,
+}
+
+impl TopicHandler for Lifecycle {
+    fn on_join(&mut self, topic: &mut Topic, client_id: u16) -> crate::Result<()> {
+        let name = topic.subscriber_data_mut::<String>(client_id).unwrap();
+        name.push_str("-joined");
+        Ok(())
+    }
+
+    fn on_leave(&mut self, topic: &mut Topic, client_id: u16) -> crate::Result<()> {
+        assert!(topic.subscriber_data::<u32>(client_id).is_none());
+        let name = topic.subscriber_data::<String>(client_id).unwrap().clone();
+        let subscriber = topic.subscribers().get(client_id).unwrap();
+        let stats = (subscriber.bytes_sent, subscriber.bytes_received);
+        self.results
+            .send((client_id, name, stats.0, stats.1))
+            .unwrap();
+        Ok(())
+    }
+}
+
+#[test]
+fn topic_subscriber_lifecycle() {
+    let (go_tx, go_rx) = mpsc::channel::<()>();
+    let (path, server) = fake_tunnel(move |mut stream| {
+        for (kind, client_id, data) in [
+            (MsgKind::ChannelSubscribe, 7, &b""[..]),
+            (MsgKind::ChannelSubscribe, 8, b""),
+            (MsgKind::ChannelData, 7, b"abcd"),
+            (MsgKind::ChannelUnsubscribe, 8, b""),
+        ] {
+            let msg = Msg::create(kind, 0, client_id, data);
+            codec::write_msg(&mut stream, &msg).unwrap();
+        }
+        go_rx.recv().unwrap();
+        let msg = Msg::create(MsgKind::ChannelUnsubscribeAll, 0, 0, Vec::new());
+        codec::write_msg(&mut stream, &msg).unwrap();
+        wait_close(&mut stream);
+    });
+    let (tx, rx) = mpsc::channel();
+    {
+        let mut topic = Topic::create("test", &path);
+        topic.set_handler(Lifecycle { results: tx });
+        topic.set_subscriber_data(|client_id| format!("client-{}", client_id));
+        topic.set_step_to(Duration::from_millis(10));
+        topic.open().unwrap();
+        for _ in 0..10 {
+            topic.step().unwrap();
+        }
+        // the data of the unsubscribed client is dropped
+        assert!(topic.subscriber_data::<String>(8).is_none());
+        let subscriber = topic.subscribers().get(7).unwrap();
+        assert_eq!(subscriber.bytes_received, 4);
+        assert!(subscriber.last_activity >= subscriber.joined_at);
+        topic.send_to(7, &b"xy"[..]).unwrap();
+        go_tx.send(()).unwrap();
+        for _ in 0..10 {
+            topic.step().unwrap();
+        }
+        assert!(topic.subscribers().is_empty());
+        assert!(topic.subscriber_data::<String>(7).is_none());
+    }
+    server.join().unwrap();
+    assert_eq!(
+        rx.try_iter().collect::<Vec<_>>(),
+        vec![
+            (8, String::from("client-8-joined"), 0, 0),
+            (7, String::from("client-7-joined"), 2, 4)
+        ]
+    );
+}
+
+#[test]
+fn topic_timers() {
+    let (tx, rx) = mpsc::channel();
+    let mut topic = Topic::create("test", "/nonexistent/latpr.sock");
+    topic.on_message(move |evt: &CallbackEvent, _: &mut Topic| {
+        if let Some(Signal::Timer(id)) = evt.signal {
+            tx.send(id).unwrap();
+        }
+        Ok(())
+    });
+    let every = topic.add_timer(Schedule::Every(Duration::from_millis(20)));
+    let once = topic.add_timer(Schedule::After(Duration::from_millis(30)));
+    let cancelled = topic.add_timer(Schedule::At(Instant::now() + Duration::from_millis(10)));
+    assert!(topic.cancel_timer(cancelled));
+    assert!(!topic.cancel_timer(cancelled));
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(110) {
+        topic.step().unwrap();
+    }
+    assert!(!topic.cancel_timer(once));
+    assert!(topic.cancel_timer(every));
+    let fired: Vec<_> = rx.try_iter().collect();
+    assert_eq!(fired.iter().filter(|id| **id == once).count(), 1);
+    assert!(!fired.contains(&cancelled));
+    let ticks = fired.iter().filter(|id| **id == every).count();
+    assert!((4..=6).contains(&ticks), "{} ticks", ticks);
+}
+
+#[test]
+fn topic_idle_with_short_timer() {
+    let (tx, rx) = mpsc::channel();
+    let mut topic = Topic::create("test", "/nonexistent/latpr.sock");
+    topic.set_handler(Recorder(tx));
+    topic.set_step_to(Duration::from_millis(50));
+    // the timer wakes the topic up before each step timeout
+    let every = topic.add_timer(Schedule::Every(Duration::from_millis(10)));
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(230) {
+        topic.step().unwrap();
+    }
+    assert!(topic.cancel_timer(every));
+    let calls: Vec<String> = rx.try_iter().collect();
+    let idle = calls.iter().filter(|call| *call == "idle").count();
+    assert!((3..=4).contains(&idle), "{} idle events", idle);
+    let ticks = calls.iter().filter(|call| *call == "timer").count();
+    assert!(ticks >= 15, "{} ticks", ticks);
+}
+
+#[test]
+fn topic_run_until_shutdown() {
+    let (path, server) = fake_tunnel(|mut stream| {
+        let msg = Msg::create(MsgKind::ChannelSubscribe, 0, 4, Vec::new());
+        codec::write_msg(&mut stream, &msg).unwrap();
+        let msgs: Vec<(MsgKind, u16)> = wait_close(&mut stream)
+            .iter()
+            .map(|msg| (msg.kind, msg.client_id))
+            .collect();
+        assert_eq!(
+            msgs,
+            vec![(MsgKind::ChannelUnsubscribe, 4), (MsgKind::ChannelClose, 0)]
+        );
+    });
+    let (tx, rx) = mpsc::channel();
+    let mut topic = Topic::create("test", &path);
+    topic.on_message(move |evt: &CallbackEvent, _: &mut Topic| {
+        if let Some(signal) = evt.signal {
+            tx.send(signal).unwrap();
+        }
+        Ok(())
+    });
+    let handle = topic.shutdown_handle().unwrap();
+    topic.open().unwrap();
+    let worker = thread::spawn(move || {
+        topic.run().unwrap();
+        topic
+    });
+    thread::sleep(Duration::from_millis(50));
+    assert!(!handle.is_shutdown());
+    handle.shutdown();
+    let topic = worker.join().unwrap();
+    assert!(!topic.is_connected());
+    server.join().unwrap();
+    drop(topic);
+    assert_eq!(
+        rx.try_iter().collect::<Vec<_>>(),
+        vec![Signal::Joined(4), Signal::Left(4), Signal::Closing]
+    );
+}
+
+#[test]
+fn reactor_run_until_shutdown() {
+    let (path_a, server_a) = fake_tunnel(|mut stream| {
+        wait_close(&mut stream);
+    });
+    let (path_b, server_b) = fake_tunnel(|mut stream| {
+        wait_close(&mut stream);
+    });
+    let mut reactor = Reactor::new().unwrap();
+    let id_a = reactor.add(Topic::create("a", &path_a)).unwrap();
+    let id_b = reactor.add(Topic::create("b", &path_b)).unwrap();
+    for id in [id_a, id_b] {
+        reactor.topic_mut(id).unwrap().open().unwrap();
+    }
+    // a single topic is closed and removed
+    reactor
+        .topic_mut(id_a)
+        .unwrap()
+        .shutdown_handle()
+        .unwrap()
+        .shutdown();
+    reactor.step().unwrap();
+    server_a.join().unwrap();
+    assert!(reactor.topic(id_a).is_none());
+    assert_eq!(reactor.len(), 1);
+    // then the whole reactor
+    let handle = reactor.shutdown_handle();
+    let worker = thread::spawn(move || {
+        reactor.run().unwrap();
+        reactor
+    });
+    thread::sleep(Duration::from_millis(50));
+    handle.shutdown();
+    let reactor = worker.join().unwrap();
+    assert!(reactor.is_empty());
+    server_b.join().unwrap();
+}
+
+#[test]
+fn reactor_isolates_failing_topic() {
+    // the tunnel of topic a dies, topic b keeps receiving data
+    let (path_a, server_a) = fake_tunnel(drop);
+    let (ready, wait) = mpsc::channel::<()>();
+    let (path_b, server_b) = fake_tunnel(move |mut stream| {
+        let _ = wait.recv();
+        let msg = Msg::create(MsgKind::ChannelData, 0, 1, b"from b".to_vec());
+        codec::write_msg(&mut stream, &msg).unwrap();
+        wait_close(&mut stream);
+    });
+    let (tx, rx) = mpsc::channel();
+    let mut reactor = Reactor::new().unwrap();
+    reactor.set_step_to(Duration::from_millis(10));
+    let id_a = reactor.add(Topic::create("a", &path_a)).unwrap();
+    let mut topic_b = Topic::create("b", &path_b);
+    topic_b.on_message(move |evt: &CallbackEvent, _: &mut Topic| {
+        if let Some(msg) = evt.msg {
+            tx.send(msg.data.to_vec()).unwrap();
+        }
+        Ok(())
+    });
+    let id_b = reactor.add(topic_b).unwrap();
+    for id in [id_a, id_b] {
+        reactor.topic_mut(id).unwrap().open().unwrap();
+    }
+    server_a.join().unwrap();
+    let mut failed = Vec::new();
+    while failed.is_empty() {
+        failed = reactor.step().unwrap();
+    }
+    assert_eq!(failed.len(), 1);
+    assert_eq!(failed[0].0, id_a);
+    assert!(matches!(failed[0].1, Error::Closed));
+    assert!(reactor.topic(id_a).is_none());
+    ready.send(()).unwrap();
+    let data = loop {
+        assert!(reactor.step().unwrap().is_empty());
+        if let Ok(data) = rx.try_recv() {
+            break data;
+        }
+    };
+    assert_eq!(data, b"from b");
+    reactor.close().unwrap();
+    server_b.join().unwrap();
+}
+
+#[test]
+fn topic_sender_from_threads() {
+    let (path, server) = fake_tunnel(|mut stream| {
+        let msg = Msg::create(MsgKind::ChannelSubscribe, 0, 9, Vec::new());
+        codec::write_msg(&mut stream, &msg).unwrap();
+        let msgs = wait_close(&mut stream);
+        let data: Vec<&Msg> = msgs
+            .iter()
+            .filter(|msg| msg.kind == MsgKind::ChannelData)
+            .collect();
+        assert_eq!(data.len(), 4 * 25 + 1);
+        assert!(data.iter().all(|msg| msg.client_id == 9));
+        assert!(msgs.iter().any(|msg| msg.kind == MsgKind::ChannelCtrl));
+    });
+    let mut topic = Topic::create("test", &path);
+    let handle = topic.shutdown_handle().unwrap();
+    let sender = topic.sender().unwrap();
+    topic.open().unwrap();
+    // wait for the subscriber
+    while !topic.is_subscribed(9) {
+        topic.step().unwrap();
+    }
+    let worker = thread::spawn(move || {
+        topic.run().unwrap();
+        topic
+    });
+    let producers: Vec<_> = (0..4)
+        .map(|_| {
+            let sender = sender.clone();
+            thread::spawn(move || {
+                for _ in 0..25 {
+                    sender.broadcast(&b"tick"[..]).unwrap();
+                }
+            })
+        })
+        .collect();
+    for producer in producers {
+        producer.join().unwrap();
+    }
+    sender.send_to(9, &b"last"[..]).unwrap();
+    sender
+        .send(Msg::create(MsgKind::ChannelCtrl, 0, 9, Vec::new()))
+        .unwrap();
+    handle.shutdown();
+    drop(worker.join().unwrap());
+    server.join().unwrap();
+    assert!(matches!(
+        sender.broadcast(&b"late"[..]),
+        Err(Error::TopicDropped)
+    ));
+}
+
+struct Service {
+    rpc: Rpc,
+    results: mpsc::Sender<String>,
+}
+
+impl TopicHandler for Service {
+    fn on_event(&mut self, evt: &CallbackEvent, topic: &mut Topic) -> crate::Result<()> {
+        if self.rpc.handle_event(evt, topic)? {
+            return Ok(());
+        }
+        handler::dispatch(self, evt, topic)
+    }
+
+    fn on_join(&mut self, topic: &mut Topic, client_id: u16) -> crate::Result<()> {
+        let results = self.results.clone();
+        let _ = self.rpc.call(
+            topic,
+            client_id,
+            "echo",
+            &b"abc"[..],
+            Duration::from_secs(5),
+            move |_, reply| {
+                let reply = reply?;
+                let reply = std::str::from_utf8(&reply)?;
+                results.send(format!("echo {}", reply)).unwrap();
+                Ok(())
+            },
+        )?;
+        let results = self.results.clone();
+        let _ = self.rpc.call(
+            topic,
+            client_id,
+            "slow",
+            Vec::new(),
+            Duration::from_millis(50),
+            move |_, reply| {
+                assert!(matches!(reply, Err(Error::Timeout)));
+                results.send(String::from("slow timeout")).unwrap();
+                Ok(())
+            },
+        )?;
+        Ok(())
+    }
+
+    fn on_data(&mut self, _: &mut Topic, client_id: u16, data: &[u8]) -> crate::Result<()> {
+        let data = String::from_utf8_lossy(data);
+        self.results
+            .send(format!("data {} {}", client_id, data))
+            .unwrap();
+        Ok(())
+    }
+}
+
+#[test]
+fn topic_rpc() {
+    let (path, server) = fake_tunnel(|mut stream| {
+        let msg = Msg::create(MsgKind::ChannelSubscribe, 0, 5, Vec::new());
+        codec::write_msg(&mut stream, &msg).unwrap();
+        for (id, method) in [(0, "ping"), (1, "fail"), (2, "nope")] {
+            let data = Envelope::request(id, method, Vec::new()).encode().unwrap();
+            let msg = Msg::create(MsgKind::ChannelData, 0, 5, data);
+            codec::write_msg(&mut stream, &msg).unwrap();
+        }
+        // other data payloads keep the fragmentation flag
+        let msg = Msg::create(MsgKind::ChannelData, 0, 5, b"\x00raw".to_vec());
+        codec::write_msg(&mut stream, &msg).unwrap();
+        let mut responses = Vec::new();
+        while responses.len() < 3 {
+            let msg = codec::read_msg(&mut stream).unwrap();
+            let envelope = Envelope::decode(&msg.data).unwrap();
+            match envelope.kind {
+                EnvelopeKind::Request if envelope.method == "echo" => {
+                    let data = Envelope::response(envelope.request_id, envelope.payload)
+                        .encode()
+                        .unwrap();
+                    let msg = Msg::create(MsgKind::ChannelData, 0, 5, data);
+                    codec::write_msg(&mut stream, &msg).unwrap();
+                }
+                // never answer the other requests
+                EnvelopeKind::Request => {}
+                EnvelopeKind::Response => responses.push((msg.kind, envelope)),
+            }
+        }
+        responses.sort_by_key(|(_, envelope)| envelope.request_id);
+        assert_eq!(responses[0].0, MsgKind::ChannelData);
+        assert_eq!(&responses[0].1.payload[..], b"pong");
+        assert_eq!(responses[1].0, MsgKind::ChannelError);
+        assert_eq!(&responses[1].1.payload[..], b"boom");
+        assert_eq!(responses[2].0, MsgKind::ChannelError);
+        assert_eq!(
+            &responses[2].1.payload[..],
+            b"RPC error: Unknown method: nope"
+        );
+        let _ = wait_close(&mut stream);
+    });
+    let (tx, rx) = mpsc::channel();
+    let mut rpc = Rpc::new();
+    rpc.register("ping", |_, client_id, _| {
+        assert_eq!(client_id, 5);
+        Ok(Bytes::from_static(b"pong"))
+    });
+    rpc.register("fail", |_, _, _| Err(Error::Other(String::from("boom"))));
+    let mut topic = Topic::create("test", &path);
+    // the envelopes are exchanged without the fragmentation flag
+    topic.set_fragmentation(FragmentPolicy::default());
+    topic.set_handler(Service { rpc, results: tx });
+    topic.open().unwrap();
+    let mut results = Vec::new();
+    let start = Instant::now();
+    while results.len() < 3 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        topic.step().unwrap();
+        results.extend(rx.try_iter());
+    }
+    drop(topic);
+    server.join().unwrap();
+    results.sort();
+    assert_eq!(results, ["data 5 raw", "echo abc", "slow timeout"]);
+}
+
+#[test]
+fn topic_ctrl_protocol() {
+    let (path, server) = fake_tunnel(|mut stream| {
+        let mut send = |kind: MsgKind, data: Vec<u8>| {
+            let msg = Msg::create(kind, 0, 1, data);
+            codec::write_msg(&mut stream, &msg).unwrap();
+        };
+        send(MsgKind::ChannelSubscribe, Vec::new());
+        for (op, payload) in [
+            (CtrlOp::Ping, &b"abc"[..]),
+            (CtrlOp::Stats, b""),
+            (CtrlOp::Info, b""),
+            (CtrlOp::App(0x81), b"cmd"),
+            (CtrlOp::App(0x82), b"other"),
+        ] {
+            send(
+                MsgKind::ChannelCtrl,
+                CtrlMsg::create(op, payload).encode().to_vec(),
+            );
+        }
+        send(MsgKind::ChannelCtrl, Vec::new());
+        let msgs = wait_close(&mut stream);
+        let replies: Vec<CtrlMsg> = msgs
+            .iter()
+            .filter(|msg| msg.kind == MsgKind::ChannelCtrl)
+            .map(|msg| CtrlMsg::decode(&msg.data).unwrap())
+            .collect();
+        assert_eq!(replies.len(), 4);
+        assert_eq!(replies[0], CtrlMsg::create(CtrlOp::Pong, &b"abc"[..]));
+        assert_eq!(replies[1].op, CtrlOp::Stats);
+        let stats = ctrl::decode_fields(&replies[1].payload);
+        assert!(stats.contains(&(String::from("client_id"), String::from("1"))));
+        assert!(stats.iter().any(|(key, _)| key == "bytes_received"));
+        assert_eq!(replies[2].op, CtrlOp::Info);
+        let info = ctrl::decode_fields(&replies[2].payload);
+        assert!(info.contains(&(String::from("name"), String::from("test"))));
+        assert!(info.contains(&(String::from("subscribers"), String::from("1"))));
+        assert_eq!(
+            replies[3],
+            CtrlMsg::create(CtrlOp::App(0x81), &b"cmd done"[..])
+        );
+    });
+    let (tx, rx) = mpsc::channel();
+    let mut topic = Topic::create("test", &path);
+    topic.on_ctrl(CtrlOp::App(0x81), |_, client_id, payload| {
+        assert_eq!(client_id, 1);
+        let mut reply = payload.to_vec();
+        reply.extend_from_slice(b" done");
+        Ok(Some(Bytes::from(reply)))
+    });
+    topic.set_handler(Recorder(tx));
+    topic.open().unwrap();
+    let mut received = Vec::new();
+    while received.len() < 3 {
+        topic.step().unwrap();
+        received.extend(rx.try_iter().filter(|evt| evt != "idle"));
+    }
+    assert_eq!(received, ["subscribe 1", "ctrl 1 \u{fffd}other", "ctrl 1 "]);
+    drop(topic);
+    server.join().unwrap();
+}
+
+/// Build the payload of a fragment
+fn fragment(id: u16, index: u16, count: u16, data: &[u8]) -> Vec<u8> {
+    let mut payload = FragmentHeader { id, index, count }.encode().to_vec();
+    payload.extend_from_slice(data);
+    payload
+}
+
+#[test]
+fn topic_fragmentation() {
+    let (path, server) = fake_tunnel(|mut stream| {
+        let mut send = |kind: MsgKind, client_id: u16, data: Vec<u8>| {
+            let msg = Msg::create(kind, 0, client_id, data);
+            codec::write_msg(&mut stream, &msg).unwrap();
+        };
+        send(MsgKind::ChannelSubscribe, 4, Vec::new());
+        // interleaved messages of two clients
+        send(MsgKind::ChannelData, 4, fragment(1, 0, 3, b"0123"));
+        send(MsgKind::ChannelData, 5, fragment(9, 0, 2, b"abcd"));
+        send(MsgKind::ChannelData, 4, fragment(1, 1, 3, b"4567"));
+        send(MsgKind::ChannelData, 5, fragment(9, 1, 2, b"ef"));
+        send(MsgKind::ChannelData, 4, fragment(1, 2, 3, b"89"));
+        // above the maximum size
+        send(MsgKind::ChannelData, 6, fragment(2, 0, 2, &[0; 16]));
+        send(MsgKind::ChannelData, 6, fragment(2, 1, 2, &[0; 16]));
+        // out of sequence
+        send(MsgKind::ChannelData, 7, fragment(3, 1, 2, b"late"));
+        // timed out
+        send(MsgKind::ChannelData, 8, fragment(4, 0, 2, b"slow"));
+        thread::sleep(Duration::from_millis(200));
+        send(MsgKind::ChannelData, 8, fragment(4, 1, 2, b"slow"));
+        // without flag
+        send(
+            MsgKind::ChannelData,
+            9,
+            b"FR\x00\x01\x00\x00\x00\x02".to_vec(),
+        );
+        send(MsgKind::ChannelData, 9, Vec::new());
+        // complete message looking like a fragment
+        let mut whole = vec![0];
+        whole.extend(fragment(5, 0, 2, b"whole"));
+        send(MsgKind::ChannelData, 4, whole);
+        send(MsgKind::ChannelData, 4, b"\x00end".to_vec());
+        let msgs = wait_close(&mut stream);
+        let data: Vec<&Msg> = msgs
+            .iter()
+            .filter(|msg| msg.kind == MsgKind::ChannelData)
+            .collect();
+        assert_eq!(data.len(), 4);
+        assert_eq!(&data[0].data[..], b"\x00\x01ab");
+        let mut payload = Vec::new();
+        for (index, msg) in data[1..].iter().enumerate() {
+            assert_eq!(msg.client_id, 4);
+            let header = FragmentHeader::decode(&msg.data).unwrap();
+            assert_eq!((header.index, header.count), (index as u16, 3));
+            payload.extend_from_slice(&msg.data[FRAGMENT_HEADER_SIZE..]);
+        }
+        assert_eq!(payload, b"reassembled");
+    });
+    let (tx, rx) = mpsc::channel();
+    let mut topic = Topic::create("test", &path);
+    topic.set_fragmentation(FragmentPolicy {
+        threshold: 4,
+        max_size: 24,
+        timeout: Duration::from_millis(50),
+    });
+    // no step timeout, the poll waits until the reassembly deadline
+    topic.on_message(move |evt, _| {
+        if let Some(msg) = evt.msg.filter(|msg| msg.kind == MsgKind::ChannelData) {
+            tx.send((msg.client_id, msg.data.to_vec())).unwrap();
+        }
+        Ok(())
+    });
+    topic.open().unwrap();
+    let mut received = Vec::new();
+    while received.last() != Some(&(4, b"end".to_vec())) {
+        topic.step().unwrap();
+        received.extend(rx.try_iter());
+    }
+    assert_eq!(
+        received,
+        [
+            (5, b"abcdef".to_vec()),
+            (4, b"0123456789".to_vec()),
+            (4, fragment(5, 0, 2, b"whole")),
+            (4, b"end".to_vec())
+        ]
+    );
+    topic.send_to(4, &b"\x01ab"[..]).unwrap();
+    topic.send_to(4, &b"reassembled"[..]).unwrap();
+    drop(topic);
+    server.join().unwrap();
+}
+
+#[cfg(any(feature = "deflate", feature = "zstd"))]
+#[test]
+fn topic_compression() {
+    use crate::tunnel::compress;
+    use crate::tunnel::{Compression, CompressionPolicy};
+    let compression = Compression::supported()[0];
+    let large = b"{\"name\":\"cpu\",\"value\":42}".repeat(64);
+    let expected = large.clone();
+    let (path, server) = fake_tunnel(move |mut stream| {
+        let mut send = |kind: MsgKind, client_id: u16, data: Vec<u8>| {
+            let msg = Msg::create(kind, 0, client_id, data);
+            codec::write_msg(&mut stream, &msg).unwrap();
+        };
+        let request = format!("compress:bogus, {}", compression.name());
+        send(MsgKind::ChannelSubscribe, 1, request.into_bytes());
+        send(MsgKind::ChannelSubscribe, 2, Vec::new());
+        send(MsgKind::ChannelCtrl, 2, b"\x05bogus".to_vec());
+        let mut data = vec![compression as u8];
+        data.extend_from_slice(&compression.compress(&expected).unwrap());
+        send(MsgKind::ChannelData, 1, data);
+        send(MsgKind::ChannelData, 1, b"\x00raw".to_vec());
+        let msgs = wait_close(&mut stream);
+        let ctrl: Vec<(u16, &[u8])> = msgs
+            .iter()
+            .filter(|msg| msg.kind == MsgKind::ChannelCtrl)
+            .map(|msg| (msg.client_id, &msg.data[..]))
+            .collect();
+        let reply = format!("\x05{}", compression.name());
+        assert_eq!(ctrl, [(1, reply.as_bytes()), (2, &b"\x05none"[..])]);
+        let data: Vec<&Msg> = msgs
+            .iter()
+            .filter(|msg| msg.kind == MsgKind::ChannelData)
+            .collect();
+        assert_eq!(data.len(), 3);
+        for msg in data {
+            match msg.client_id {
+                1 if msg.data.len() < 16 => assert_eq!(&msg.data[..], b"\x00tiny"),
+                1 => {
+                    assert_eq!(msg.data[0], compression as u8);
+                    assert!(msg.data.len() < expected.len() / 4);
+                    let data = compress::decode(&msg.data, 1 << 20).unwrap();
+                    assert_eq!(&data[..], &expected[..]);
+                }
+                _ => assert_eq!(&msg.data[..], &expected[..]),
+            }
+        }
+    });
+    let (tx, rx) = mpsc::channel();
+    let mut topic = Topic::create("test", &path);
+    topic.set_compression(CompressionPolicy {
+        threshold: 64,
+        max_size: 1 << 20,
+    });
+    topic.on_message(move |evt, _| {
+        if let Some(msg) = evt.msg.filter(|msg| msg.kind == MsgKind::ChannelData) {
+            tx.send((msg.client_id, msg.data.to_vec())).unwrap();
+        }
+        Ok(())
+    });
+    topic.open().unwrap();
+    let mut received = Vec::new();
+    while received.len() < 2 {
+        topic.step().unwrap();
+        received.extend(rx.try_iter());
+    }
+    assert_eq!(received, [(1, large.clone()), (1, b"raw".to_vec())]);
+    assert_eq!(
+        topic.subscribers().get(1).unwrap().compression,
+        Some(compression)
+    );
+    assert_eq!(topic.subscribers().get(2).unwrap().compression, None);
+    topic.broadcast(large).unwrap();
+    topic.send_to(1, &b"tiny"[..]).unwrap();
+    drop(topic);
+    server.join().unwrap();
+}
+
+#[cfg(any(feature = "json", feature = "cbor", feature = "msgpack"))]
+#[test]
+fn typed_topic() {
+    use crate::tunnel::{Format, TypedTopic};
+    let formats = [
+        #[cfg(feature = "json")]
+        Format::Json,
+        #[cfg(feature = "cbor")]
+        Format::Cbor,
+        #[cfg(feature = "msgpack")]
+        Format::MsgPack,
+    ];
+    for format in formats {
+        let (path, server) = fake_tunnel(move |mut stream| {
+            let msg = Msg::create(MsgKind::ChannelSubscribe, 0, 3, Vec::new());
+            codec::write_msg(&mut stream, &msg).unwrap();
+            let msg = Msg::create(MsgKind::ChannelData, 0, 3, vec![0xff, 0x00, 0x12]);
+            codec::write_msg(&mut stream, &msg).unwrap();
+            let data = format
+                .encode(&(String::from("sum"), vec![1u32, 2, 3]))
+                .unwrap();
+            let msg = Msg::create(MsgKind::ChannelData, 0, 3, data);
+            codec::write_msg(&mut stream, &msg).unwrap();
+            let msgs = wait_close(&mut stream);
+            let reply: Vec<&Msg> = msgs
+                .iter()
+                .filter(|msg| msg.kind == MsgKind::ChannelData)
+                .collect();
+            assert_eq!(reply.len(), 1);
+            assert_eq!(reply[0].client_id, 3);
+            let value: (String, u32) = format.decode(&reply[0].data).unwrap();
+            assert_eq!(value, (String::from("sum"), 6));
+            let error = msgs
+                .iter()
+                .find_map(|msg| msg.error_text())
+                .expect("no error reply");
+            assert!(error.starts_with("Serialization error"));
+        });
+        let (tx, rx) = mpsc::channel();
+        let mut topic: TypedTopic<(String, Vec<u32>), (String, u32)> =
+            TypedTopic::create(Topic::create("test", &path), format);
+        assert_eq!(topic.format(), format);
+        topic.on_value(move |_, client_id, value| {
+            tx.send((client_id, value)).unwrap();
+            Ok(())
+        });
+        topic.open().unwrap();
+        let (client_id, (name, values)) = loop {
+            topic.step().unwrap();
+            if let Ok(value) = rx.try_recv() {
+                break value;
+            }
+        };
+        assert_eq!(client_id, 3);
+        // the invalid payload is answered before the valid one is decoded
+        topic
+            .send_to(client_id, &(name, values.iter().sum()))
+            .unwrap();
+        assert!(rx.try_recv().is_err());
+        drop(topic);
+        server.join().unwrap();
+    }
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn async_topic_send_recv() {
+    use crate::tunnel::AsyncTopic;
+    let (path, server) = fake_tunnel(|mut stream| {
+        codec::write_msg(&mut stream, &sample_msg()).unwrap();
+        let msgs = wait_close(&mut stream);
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(msgs[0].kind, MsgKind::ChannelData);
+        assert_eq!(&msgs[0].data[..], b"world");
+    });
+    let mut topic = AsyncTopic::open("test", &path).await.unwrap();
+    let msg = topic.recv().await.unwrap().unwrap();
+    assert_eq!(&msg.data[..], b"hello");
+    topic
+        .send(Msg::create(MsgKind::ChannelData, 0, 1, b"world".to_vec()))
+        .await
+        .unwrap();
+    topic.close().await.unwrap();
+    server.join().unwrap();
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn async_topic_ends_after_error() {
+    use crate::tunnel::AsyncTopic;
+    let (path, server) = fake_tunnel(|mut stream| {
+        stream.write_all(&[0xFF; 32]).unwrap();
+        codec::write_msg(&mut stream, &sample_msg()).unwrap();
+        wait_close(&mut stream);
+    });
+    let mut topic = AsyncTopic::open("test", &path).await.unwrap();
+    assert!(matches!(
+        topic.recv().await,
+        Some(Err(Error::Protocol { .. }))
+    ));
+    assert!(topic.recv().await.is_none());
+    assert!(topic.recv().await.is_none());
+    topic.close().await.unwrap();
+    server.join().unwrap();
+}
@@ -1,21 +1,73 @@
+use crate::error::{Error, Result};
 use crate::utils::{LogLevel, LOG};
-use crate::{ERR, ERROR, EXIT, INFO, WARN};
+use crate::{ERROR, INFO, WARN};
+use bytes::Buf;
+use codec::Decoder;
+use ctrl::CtrlHandle;
+use fragment::{Fragmenter, Reassembled};
 use mio::event::Event;
 use mio::unix::SourceFd;
-use mio::{Events, Interest, Poll, Token};
-use std::collections::HashMap;
-use std::error::Error;
-use std::io::{Read, Write};
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
+use rpc::Envelope;
+use sender::{Command, SENDER_QUEUE_SIZE};
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::io::{ErrorKind, IoSlice, Write};
 use std::net::Shutdown;
-use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
 use std::os::unix::net::UnixStream;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
+use subscriber::SubscriberInit;
+use timer::Timers;
 
-const MSG_MAGIC_BEGIN: u16 = 0x414e;
-const MSG_MAGIC_END: u16 = 0x5444;
-const SERVER: Token = Token(0);
+#[cfg(feature = "async")]
+pub mod async_topic;
+pub mod codec;
+pub mod compress;
+pub mod ctrl;
+pub mod fragment;
+pub mod handler;
+pub mod reactor;
+pub mod rpc;
+pub mod sender;
+pub mod shutdown;
+pub mod subscriber;
+pub mod timer;
+#[cfg(any(feature = "json", feature = "cbor", feature = "msgpack"))]
+pub mod typed;
+
+#[cfg(feature = "async")]
+pub use async_topic::AsyncTopic;
+pub use bytes::Bytes;
+pub use compress::{Compression, CompressionPolicy};
+pub use ctrl::{CtrlMsg, CtrlOp};
+pub use fragment::FragmentPolicy;
+pub use handler::TopicHandler;
+pub use reactor::{Reactor, TopicId};
+pub use rpc::Rpc;
+pub use sender::TopicSender;
+pub use shutdown::ShutdownHandle;
+pub use subscriber::{Subscriber, SubscriberData, SubscriberMap};
+pub use timer::{Schedule, TimerId};
+#[cfg(any(feature = "json", feature = "cbor", feature = "msgpack"))]
+pub use typed::{Format, TypedHandler, TypedTopic};
+
+/// Number of token bits reserved to each topic, the upper
+/// bits of a token identify the topic in a `Reactor`
+const TOPIC_TOKEN_BITS: u32 = 16;
+/// Token of the waker of a poll object, out of the range of the topics
+const WAKER: Token = Token(usize::MAX);
 const MAX_EVT_CAPACITY: usize = 128;
+const READ_BUFFER_SIZE: usize = 4096;
+const MAX_IOV: usize = 64;
+/// Maximum time spent flushing the outbound queue when the channel is closed
+const CLOSE_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+/// Default size of the outbound queue above which data messages are rejected
+pub const DEFAULT_HIGH_WATER_MARK: usize = 1 << 20;
 
 pub type IOInterest = Interest;
 pub type IOEvent = Event;
@@ -23,429 +75,2071 @@ pub type IOEvent = Event;
 //pub type IoCallback = dyn Fn(&RawFd, &IOEvent) -> Option<Msg>;
 /// Different message  type
 ///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MsgKind {
     /// OK
     ChannelOk,
     /// Error header
     ChannelError,
+    // Subscribe to a channel
+    ChannelSubscribe,
+    // Unsubscribe a channel
+    ChannelUnsubscribe,
     /// Open a channel
     ChannelOpen,
     // Close channel
     ChannelClose,
     // Data
     ChannelData,
-    // Unsubscribe a channel
-    ChannelUnsubscribe,
-    // Subscribe to a channel
-    ChannelSubscribe,
     // CTRL
     ChannelCtrl,
-    // Unknown Msg type,
+    // Unsubscribe all clients of a channel
     ChannelUnsubscribeAll,
-    Unknown,
+    // Unknown Msg type, only produced by lenient decoding
+    Unknown(u8),
+}
+
+/// Notifications generated by the topic itself
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    /// The outbound queue has been flushed after being congested,
+    /// data messages are accepted again
+    Drained,
+    /// The tunnel connection has been re-established and the channel
+    /// re-opened, all previous subscribers are lost
+    Reconnected,
+    /// Corrupted data has been dropped from the tunnel stream to
+    /// resynchronise on the next frame, holds the number of dropped bytes
+    Resync(u64),
+    /// Frames above the maximum payload size have been discarded,
+    /// holds the number of discarded frames
+    Discarded(u64),
+    /// The topic is being closed, the channel is closed once
+    /// the callback returns
+    Closing,
+    /// A client has subscribed to the channel, holds the client id
+    Joined(u16),
+    /// A client is leaving the channel, holds the client id. The
+    /// subscriber is removed from the topic once the callback returns
+    Left(u16),
+    /// A timer added with `Topic::add_timer` has expired
+    Timer(TimerId),
+    /// The tunnel connection is lost (closed, broken or unresponsive),
+    /// a reconnection is attempted if a policy is set
+    Disconnected,
+}
+
+/// Reconnection policy of a `Topic`
+///
+/// The delay between two attempts starts at `initial_delay` and
+/// is doubled after each failure, up to `max_delay`
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    /// Delay before the first attempt
+    pub initial_delay: Duration,
+    /// Maximum delay between two attempts
+    pub max_delay: Duration,
+    /// Maximum number of attempts, `None` for no limit
+    pub max_attempts: Option<u32>,
+    /// Maximum time to wait for the confirmation of the channel
+    /// opening, the attempt fails after this delay
+    pub handshake_timeout: Duration,
+}
+
+/// Heartbeat of a `Topic`
+///
+/// A `Ping` control message is sent to the tunnel service (client
+/// id `0`) when nothing has been received for `interval`. Any
+/// traffic from the tunnel keeps the connection alive, the
+/// connection is declared lost when nothing has been received
+/// for `timeout`
+#[derive(Debug, Clone)]
+pub struct HeartbeatPolicy {
+    /// Idle time before a ping is sent
+    pub interval: Duration,
+    /// Idle time before the connection is declared lost
+    pub timeout: Duration,
 }
 
 pub struct CallbackEvent<'c> {
     pub fd: Option<RawFd>,
     pub event: Option<&'c IOEvent>,
     pub msg: Option<&'c Msg>,
+    pub signal: Option<Signal>,
 }
 
-pub struct Topic<'a> {
-    pub name: &'a str,
-    pub socket_file: &'a str,
+pub struct Topic {
+    pub name: String,
+    pub socket_file: String,
     channel: Option<UnixStream>,
+    decoder: Decoder,
+    outbound: VecDeque<Bytes>,
+    outbound_len: usize,
+    high_water_mark: usize,
+    congested: bool,
+    writable: bool,
     poll: Option<Poll>,
-    msg_handle: Option<&'a mut dyn FnMut(&CallbackEvent, &mut Topic<'a>) -> Result<(), Box<dyn Error>>>,
-    io_fds: HashMap<Token, RawFd>,
+    registry: Option<Registry>,
+    token_base: usize,
+    msg_handle: Option<Box<dyn TopicHandler>>,
+    io_fds: HashMap<Token, (RawFd, Interest)>,
     stepto: Option<Duration>,
+    last_active: Instant,
     n_token: usize,
+    reconnect: Option<ReconnectPolicy>,
+    retry: Option<(Instant, u32)>,
+    handshake: bool,
+    heartbeat: Option<HeartbeatPolicy>,
+    last_seen: Instant,
+    last_ping: Instant,
+    subscribers: SubscriberMap<SubscriberData>,
+    subscriber_init: Option<Box<SubscriberInit>>,
+    timers: Timers,
+    waker: Option<Arc<Waker>>,
+    shutdown: Arc<AtomicBool>,
+    closed: bool,
+    inbox: Option<(SyncSender<Command>, Receiver<Command>)>,
+    fragmenter: Option<Fragmenter>,
+    compression: Option<CompressionPolicy>,
+    ctrl_handles: HashMap<CtrlOp, Box<CtrlHandle>>,
+}
+
+/// State of the tunnel socket after a read
+enum ReadState {
+    /// More data may be available
+    Pending,
+    /// No more data until the next readiness event
+    WouldBlock,
+    /// The socket has been closed by the tunnel server
+    Closed,
 }
 
+#[derive(Debug)]
 pub struct Msg {
     pub kind: MsgKind,
     pub channel_id: u16,
     pub client_id: u16,
     pub size: u32,
-    pub data: Vec<u8>,
+    pub data: Bytes,
 }
 
 impl<'b> CallbackEvent<'b> {
     pub fn create(fd: Option<RawFd>, event: Option<&'b IOEvent>, msg: Option<&'b Msg>) -> Self {
-        CallbackEvent { fd, event, msg }
+        CallbackEvent {
+            fd,
+            event,
+            msg,
+            signal: None,
+        }
+    }
+
+    /// Create an event carrying a topic `Signal`
+    ///
+    /// Arguments
+    ///
+    /// * `signal` - the signal
+    pub fn signal(signal: Signal) -> Self {
+        CallbackEvent {
+            fd: None,
+            event: None,
+            msg: None,
+            signal: Some(signal),
+        }
     }
 }
 
 impl MsgKind {
-    /// convert a u8 value to `MsgKind` value
+    /// convert a u8 value to `MsgKind` value, leniently
+    ///
+    /// Unsupported values are mapped to `MsgKind::Unknown`
     ///
     /// # Arguments
     ///
     /// * `value` - u8 header value
-    fn from_u8(value: u8) -> Self {
-        match value {
-            0x0 => MsgKind::ChannelOk,
-            0x1 => MsgKind::ChannelError,
-            0x2 => MsgKind::ChannelSubscribe,
-            0x3 => MsgKind::ChannelUnsubscribe,
-            0x4 => MsgKind::ChannelOpen,
-            0x5 => MsgKind::ChannelClose,
-            0x6 => MsgKind::ChannelData,
-            0x7 => MsgKind::ChannelCtrl,
-            0xA => MsgKind::ChannelUnsubscribeAll,
-            _ => MsgKind::Unknown,
-        }
+    pub fn from_u8(value: u8) -> Self {
+        MsgKind::try_from(value).unwrap_or(MsgKind::Unknown(value))
     }
+}
+
+/// Implement the conversions between `MsgKind` and its header
+/// value from a single table
+macro_rules! msg_kind_values {
+    ($($kind:ident = $value:literal),* $(,)?) => {
+        impl TryFrom<u8> for MsgKind {
+            type Error = Error;
 
-    /// convert a `MsgKind` value to u8 value
+            /// convert a u8 value to `MsgKind` value
+            ///
+            /// # Arguments
+            ///
+            /// * `value` - u8 header value
+            ///
+            /// # Errors
+            ///
+            /// * `Error::InvalidKind` - the value is not a supported message kind
+            fn try_from(value: u8) -> Result<Self> {
+                match value {
+                    $($value => Ok(MsgKind::$kind),)*
+                    _ => Err(Error::InvalidKind(value)),
+                }
+            }
+        }
+
+        impl From<MsgKind> for u8 {
+            /// convert a `MsgKind` value to u8 value
+            ///
+            /// # Arguments
+            ///
+            /// * `kind` - MsgKind
+            fn from(kind: MsgKind) -> Self {
+                match kind {
+                    $(MsgKind::$kind => $value,)*
+                    MsgKind::Unknown(value) => value,
+                }
+            }
+        }
+    };
+}
+
+msg_kind_values! {
+    ChannelOk = 0x0,
+    ChannelError = 0x1,
+    ChannelSubscribe = 0x2,
+    ChannelUnsubscribe = 0x3,
+    ChannelOpen = 0x4,
+    ChannelClose = 0x5,
+    ChannelData = 0x6,
+    ChannelCtrl = 0x7,
+    ChannelUnsubscribeAll = 0xA,
+}
+
+impl ReconnectPolicy {
+    /// Delay before a reconnection attempt
     ///
-    /// # Arguments
+    /// Arguments
     ///
-    /// * `kind` - MsgKind
-    fn to_u8(kind: &Self) -> u8 {
-        match kind {
-            MsgKind::ChannelOk => 0x0,
-            MsgKind::ChannelError => 0x1,
-            MsgKind::ChannelSubscribe => 0x2,
-            MsgKind::ChannelUnsubscribe => 0x3,
-            MsgKind::ChannelOpen => 0x4,
-            MsgKind::ChannelClose => 0x5,
-            MsgKind::ChannelData => 0x6,
-            MsgKind::ChannelCtrl => 0x7,
-            MsgKind::ChannelUnsubscribeAll => 0xA,
-            MsgKind::Unknown => 0xFF,
+    /// * `attempt` - number of failed attempts so far
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempt).unwrap_or(u32::MAX);
+        self.initial_delay
+            .checked_mul(factor)
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay))
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(30),
+            max_attempts: None,
+            handshake_timeout: Duration::from_secs(5),
         }
     }
 }
 
-impl<'a> Topic<'a> {
+impl Default for HeartbeatPolicy {
+    fn default() -> Self {
+        HeartbeatPolicy {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Topic {
     /// Create new `Topic` object
     ///
     /// Arguments
     ///
     /// * `name` - a topic name
     /// * `socket_file` - a a path to tunnel socket
-    pub fn create(name: &'a str, socket_file: &'a str) -> Self {
+    pub fn create(name: impl Into<String>, socket_file: impl Into<String>) -> Self {
         Topic {
-            name,
-            socket_file,
+            name: name.into(),
+            socket_file: socket_file.into(),
             channel: None,
+            decoder: Decoder::new(),
+            outbound: VecDeque::new(),
+            outbound_len: 0,
+            high_water_mark: DEFAULT_HIGH_WATER_MARK,
+            congested: false,
+            writable: false,
             poll: None,
+            registry: None,
+            token_base: 0,
             msg_handle: None,
             io_fds: HashMap::new(),
             stepto: None,
+            last_active: Instant::now(),
             n_token: 1,
+            reconnect: None,
+            retry: None,
+            handshake: false,
+            heartbeat: None,
+            last_seen: Instant::now(),
+            last_ping: Instant::now(),
+            subscribers: SubscriberMap::new(),
+            subscriber_init: None,
+            timers: Timers::default(),
+            waker: None,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            closed: false,
+            inbox: None,
+            fragmenter: None,
+            compression: None,
+            ctrl_handles: HashMap::new(),
         }
     }
 
     /// Open a tunnel for the topic
     ///
-    pub fn open(&mut self) -> Result<(), Box<dyn Error>> {
+    /// # Errors
+    ///
+    /// * `Error::ChannelRefused` - the tunnel service does not confirm the
+    ///   channel opening, the error holds the response message
+    /// * `Error::Io` - unable to connect to the tunnel socket
+    pub fn open(&mut self) -> Result<()> {
+        self.connect()?;
+        INFO!("Channel {} opened sucessfully", self.name);
+        Ok(())
+    }
+
+    /// Connect to the tunnel socket, open the channel and
+    /// register the socket to polling
+    ///
+    fn connect(&mut self) -> Result<()> {
         INFO!("Open unix domain socket: {}", self.socket_file);
-        let sock = UnixStream::connect(self.socket_file)?;
-        let fd = sock.as_raw_fd();
-        self.channel = Some(sock);
+        let sock = UnixStream::connect(&self.socket_file)?;
+        self.attach_channel(sock);
         // send a channel open
-        let rq = Msg::create(MsgKind::ChannelOpen, 0, 0, self.name.as_bytes().to_vec());
-        self.write(&rq)?;
+        self.write(&self.open_msg())?;
         // wait for confirm
         INFO!(
             "Wait for comfirm channel opening from: {}",
             self.socket_file
         );
         let response = self.read()?;
-        match response.kind {
-            MsgKind::ChannelOk => {}
-            _ => {
-                let _ = self.close();
-                EXIT!(
-                    "Channel is not created: %s. Tunnel service responds with msg of type {}",
-                    response.kind
-                );
-            }
+        if !matches!(response.kind, MsgKind::ChannelOk) {
+            self.refused(&response);
+            let _ = self.close_channel();
+            self.disconnect();
+            return Err(Error::ChannelRefused(response));
         }
-        // add socket to polling
-        let poll = self.get_poll()?;
-        poll.registry()
-            .register(&mut SourceFd(&fd), SERVER, Interest::READABLE)?;
-        let _ = self.io_fds.insert(SERVER, fd);
-        INFO!("Channel {} opened sucessfully", self.name);
+        // from now on, the socket is read incrementally in `step`
+        self.channel
+            .as_ref()
+            .ok_or(Error::NotConnected)?
+            .set_nonblocking(true)?;
+        self.register_channel()
+    }
+
+    /// Connect to the tunnel socket and send the channel opening
+    /// without waiting for the confirmation
+    ///
+    /// The confirmation is read from the socket by `handle_event`
+    fn start_connect(&mut self) -> Result<()> {
+        INFO!("Open unix domain socket: {}", self.socket_file);
+        // a tunnel that does not accept connections fails the attempt
+        let sock = mio::net::UnixStream::connect(&self.socket_file)?;
+        // the descriptor is moved out of the mio stream
+        let sock = unsafe { UnixStream::from_raw_fd(sock.into_raw_fd()) };
+        self.attach_channel(sock);
+        self.register_channel()?;
+        self.write(&self.open_msg())?;
+        self.handshake = true;
         Ok(())
     }
 
-    /// Read and check the number if any
+    /// Use a new socket as tunnel connection
     ///
     /// Arguments
     ///
-    /// * `number` - the number to check, 0 if not check
-    fn read_u16_number(&self, number: u16) -> Result<u16, Box<dyn Error>> {
-        let mut buf: [u8; 2] = [0; 2];
-        self.channel
-            .as_ref()
-            .ok_or("Invalid read channel")?
-            .read_exact(&mut buf)?;
-        let retnum = u16::from_be_bytes(buf);
-        if (number != 0) && (retnum != number) {
-            return Err(ERR!(format!(
-                "Read number mismatched, expected {:#04x} got {:#04x}",
-                number, retnum
-            )));
-        }
-        Ok(retnum)
+    /// * `sock` - the socket
+    fn attach_channel(&mut self, sock: UnixStream) {
+        self.channel = Some(sock);
+        self.closed = false;
+        self.handshake = false;
+        self.decoder.clear();
+        self.outbound.clear();
+        self.outbound_len = 0;
+        self.congested = false;
+        self.writable = false;
+        self.last_seen = Instant::now();
+        self.last_ping = self.last_seen;
     }
 
-    /// Read u32 number
+    /// Register the tunnel socket to polling
     ///
-    fn read_u32_number(&self) -> Result<u32, Box<dyn Error>> {
-        let mut buf: [u8; 4] = [0; 4];
-        self.channel
+    fn register_channel(&mut self) -> Result<()> {
+        let fd = self
+            .channel
             .as_ref()
-            .ok_or("Invalid read channel")?
-            .read_exact(&mut buf)?;
-        Ok(u32::from_be_bytes(buf))
+            .ok_or(Error::NotConnected)?
+            .as_raw_fd();
+        let token = self.server_token();
+        self.registry()?
+            .register(&mut SourceFd(&fd), token, Interest::READABLE)?;
+        let _ = self.io_fds.insert(token, (fd, Interest::READABLE));
+        Ok(())
     }
-    /// Read message type
+
+    /// Message opening the channel of the topic
+    fn open_msg(&self) -> Msg {
+        Msg::create(
+            MsgKind::ChannelOpen,
+            0,
+            0,
+            Bytes::copy_from_slice(self.name.as_bytes()),
+        )
+    }
+
+    /// Log the refusal of the channel opening
     ///
-    fn read_kind(&self) -> Result<MsgKind, Box<dyn Error>> {
-        let mut buf: [u8; 1] = [0];
-        self.channel
-            .as_ref()
-            .ok_or("Invalid read channel")?
-            .read_exact(&mut buf)?;
-        if buf[0] > 0x7 {
-            return Err(ERR!(format!("Invalid msg type {:#02x}", buf[0])));
+    /// Arguments
+    ///
+    /// * `response` - the response of the tunnel service
+    fn refused(&self, response: &Msg) {
+        match response.error_text() {
+            Some(text) => ERROR!("Channel {} is not created: {}", self.name, text),
+            None => ERROR!(
+                "Channel {} is not created. Tunnel service responds with msg of type {}",
+                self.name,
+                response.kind
+            ),
         }
-        Ok(MsgKind::from_u8(buf[0]))
     }
 
-    /// Read a message from the socket
+    /// Drop the current tunnel connection without sending
+    /// any message to the tunnel server
     ///
-    fn read(&self) -> Result<Msg, Box<dyn Error>> {
-        let _ = self.read_u16_number(MSG_MAGIC_BEGIN)?;
-        let kind: MsgKind = self.read_kind()?;
-        let channel_id: u16 = self.read_u16_number(0)?;
-        let client_id: u16 = self.read_u16_number(0)?;
-        let size: u32 = self.read_u32_number()?;
-        let mut payload = vec![0; size as usize];
-        // read all the payload data
-        self.channel
-            .as_ref()
-            .ok_or("Invalid read channel")?
-            .read_exact(&mut payload)?;
+    fn disconnect(&mut self) {
+        if let Some(sock) = self.channel.take() {
+            if self.io_fds.remove(&self.server_token()).is_some() {
+                if let Some(registry) = self.registry.as_ref() {
+                    let _ = registry.deregister(&mut SourceFd(&sock.as_raw_fd()));
+                }
+            }
+            let _ = sock.shutdown(Shutdown::Both);
+        }
+        self.decoder.clear();
+        self.outbound.clear();
+        self.outbound_len = 0;
+        self.congested = false;
+        self.writable = false;
+        self.handshake = false;
+        // the subscribers are lost with the connection
+        self.subscribers.clear();
+        if let Some(fragmenter) = self.fragmenter.as_mut() {
+            fragmenter.clear();
+        }
+    }
 
-        let _ = self.read_u16_number(MSG_MAGIC_END)?;
+    /// Handle a broken tunnel connection
+    ///
+    /// The callback receives a `Signal::Disconnected` event and a
+    /// `Signal::Left` event for each subscriber, then the connection
+    /// is dropped. The error is returned as is if no reconnection
+    /// policy is set, otherwise the first reconnection attempt is
+    /// scheduled
+    ///
+    /// Arguments
+    ///
+    /// * `error` - the connection error
+    fn connection_lost(&mut self, error: Error) -> Result<()> {
+        if self.handshake {
+            return self.reconnect_failed(error);
+        }
+        let evt = CallbackEvent::signal(Signal::Disconnected);
+        let result = self.execute_event(&evt).and_then(|_| self.leave_all());
+        // the socket is dropped even if the callback fails, a dead
+        // tunnel must not be written to when the topic is closed
+        self.disconnect();
+        let policy = match self.reconnect.as_ref() {
+            None => return result.and(Err(error)),
+            Some(policy) => policy,
+        };
+        WARN!(
+            "Tunnel connection of topic {} is lost: {}. Reconnect in {:?}",
+            self.name,
+            error,
+            policy.delay(0)
+        );
+        self.retry = Some((Instant::now() + policy.delay(0), 0));
+        result
+    }
 
-        let msg = Msg::create(kind, channel_id, client_id, payload);
-        Ok(msg)
+    /// Try to reconnect if a reconnection attempt is due
+    ///
+    /// The attempt fails if the channel opening is not confirmed
+    /// within the handshake timeout of the policy
+    fn try_reconnect(&mut self) -> Result<()> {
+        let (deadline, attempt) = match self.retry {
+            Some(retry) => retry,
+            None => return Ok(()),
+        };
+        let policy = match self.reconnect.as_ref() {
+            Some(policy) => policy,
+            None => return Ok(()),
+        };
+        if Instant::now() < deadline {
+            return Ok(());
+        }
+        if self.handshake {
+            return self.reconnect_failed(Error::Timeout);
+        }
+        let deadline = Instant::now() + policy.handshake_timeout;
+        match self.start_connect() {
+            Ok(()) => {
+                self.retry = Some((deadline, attempt));
+                Ok(())
+            }
+            Err(error) => self.reconnect_failed(error),
+        }
     }
 
-    /// Write a message to the socket
+    /// Process the response of the tunnel service to the channel
+    /// opening of a reconnection attempt
+    ///
+    /// Return false if the channel is refused
     ///
     /// Arguments
     ///
-    /// * `msg` - a message
-    pub fn write(&self, msg: &Msg) -> Result<(), Box<dyn Error>> {
-        let mut sock = self.channel.as_ref().ok_or("Invalid write channel")?;
-        // write the magic begin
-        sock.write_all(&MSG_MAGIC_BEGIN.to_be_bytes())?;
-        sock.write_all(&[MsgKind::to_u8(&msg.kind)])?;
-        sock.write_all(&msg.channel_id.to_be_bytes())?;
-        sock.write_all(&msg.client_id.to_be_bytes())?;
-        sock.write_all(&msg.size.to_be_bytes())?;
-        if msg.size != 0 {
-            sock.write_all(&msg.data)?;
-        }
-        sock.write_all(&MSG_MAGIC_END.to_be_bytes())?;
-        Ok(())
+    /// * `response` - the response
+    fn handshake_done(&mut self, response: Msg) -> Result<bool> {
+        if !matches!(response.kind, MsgKind::ChannelOk) {
+            self.refused(&response);
+            self.reconnect_failed(Error::ChannelRefused(response))?;
+            return Ok(false);
+        }
+        let attempt = self.retry.map_or(0, |(_, attempt)| attempt);
+        INFO!(
+            "Channel {} reopened after {} attempt(s)",
+            self.name,
+            attempt + 1
+        );
+        self.handshake = false;
+        self.retry = None;
+        let evt = CallbackEvent::signal(Signal::Reconnected);
+        self.execute_event(&evt)?;
+        Ok(true)
     }
 
-    /// Close the tunnel
+    /// Drop the connection of a failed reconnection attempt and
+    /// schedule the next one
     ///
-    fn close(&self) -> Result<(), Box<dyn Error>> {
-        INFO!("Closing the channel: {}", self.name);
-        let rq = Msg::create(MsgKind::ChannelClose, 0, 0, vec![]);
-        if let Err(error) = self.write(&rq) {
-            WARN!("Unable to write close message to tunnel server {}", error);
+    /// Arguments
+    ///
+    /// * `error` - the error of the attempt
+    ///
+    /// # Errors
+    ///
+    /// * `Error::Reconnect` - the maximum number of attempts is reached
+    fn reconnect_failed(&mut self, error: Error) -> Result<()> {
+        self.disconnect();
+        let attempt = self.retry.map_or(0, |(_, attempt)| attempt) + 1;
+        let policy = match self.reconnect.as_ref() {
+            Some(policy) => policy,
+            None => return Err(error),
+        };
+        if let Some(max) = policy.max_attempts {
+            if attempt >= max {
+                self.retry = None;
+                return Err(Error::Reconnect {
+                    attempts: attempt,
+                    source: Box::new(error),
+                });
+            }
         }
-        self.channel
-            .as_ref()
-            .ok_or("Channel is not created")?
-            .shutdown(Shutdown::Both)?;
+        WARN!(
+            "Unable to reconnect topic {}: {}. Retry in {:?}",
+            self.name,
+            error,
+            policy.delay(attempt)
+        );
+        self.retry = Some((Instant::now() + policy.delay(attempt), attempt));
         Ok(())
     }
 
-    pub fn on_message(&mut self, callback: &'a mut impl FnMut(&CallbackEvent, &mut Topic<'a>) -> Result<(), Box<dyn Error>>) {
-        self.msg_handle = Some(callback);
+    /// Enable automatic reconnection when the tunnel connection is lost
+    ///
+    /// Once reconnected, the channel is re-opened and the callback
+    /// receives a `Signal::Reconnected` event
+    ///
+    /// Arguments
+    ///
+    /// * `policy` - the reconnection policy
+    pub fn set_reconnect(&mut self, policy: ReconnectPolicy) {
+        self.reconnect = Some(policy);
+    }
+
+    /// Enable the heartbeat detecting an unresponsive tunnel service
+    ///
+    /// When the connection is declared lost, the callback receives a
+    /// `Signal::Disconnected` event and the topic is reconnected if a
+    /// reconnection policy is set, otherwise `step` returns
+    /// `Error::Unresponsive`
+    ///
+    /// Arguments
+    ///
+    /// * `policy` - the heartbeat policy
+    pub fn set_heartbeat(&mut self, policy: HeartbeatPolicy) {
+        self.heartbeat = Some(policy);
     }
 
-    fn get_poll(&mut self) -> Result<&mut Poll, Box<dyn Error>> {
-        if let None = self.poll {
-            self.poll = Some(Poll::new()?);
+    /// Ping the tunnel service or declare the connection lost
+    /// if it has been silent for too long
+    ///
+    /// Arguments
+    ///
+    /// * `now` - the current time
+    fn check_heartbeat(&mut self, now: Instant) -> Result<()> {
+        let policy = match self.heartbeat.as_ref() {
+            Some(policy) if self.is_connected() => policy.clone(),
+            _ => return Ok(()),
+        };
+        let idle = now.saturating_duration_since(self.last_seen);
+        if idle >= policy.timeout {
+            return self.connection_lost(Error::Unresponsive(idle));
+        }
+        if now.saturating_duration_since(self.last_ping.max(self.last_seen)) < policy.interval {
+            return Ok(());
+        }
+        self.last_ping = now;
+        let ping = CtrlMsg::create(CtrlOp::Ping, Bytes::new());
+        match self.send_ctrl(0, &ping) {
+            Err(error @ Error::Io(_)) => self.connection_lost(error),
+            result => result,
         }
-        Ok(self.poll.as_mut().ok_or("Invalid poll object")?)
     }
-    pub fn register_io(&mut self, fd: RawFd, interest: IOInterest) -> Result<(), Box<dyn Error>> {
-        // add socket to polling
-        let token = Token(self.n_token);
-        self.get_poll()?
-            .registry()
-            .register(&mut SourceFd(&fd), token, interest)?;
-        // register the handle
-        let _ = self.io_fds.insert(token, fd);
-        self.n_token = self.n_token + 1;
-        Ok(())
+
+    /// Next time the heartbeat has to be checked
+    fn heartbeat_deadline(&self) -> Option<Instant> {
+        let policy = self.heartbeat.as_ref()?;
+        if !self.is_connected() {
+            return None;
+        }
+        let ping = self.last_ping.max(self.last_seen) + policy.interval;
+        Some(ping.min(self.last_seen + policy.timeout))
     }
-    pub fn unregister_io(&mut self, fd: RawFd) -> Result<(), Box<dyn Error>> {
-        self.get_poll()?
-            .registry()
-            .deregister(&mut SourceFd(&fd))?;
-        // remove by value
-        self.io_fds.retain(|_, v| *v != fd);
-        Ok(())
+
+    /// Deliver messages of unsupported kinds to the callback as
+    /// `MsgKind::Unknown` instead of failing
+    ///
+    /// Arguments
+    ///
+    /// * `lenient` - true to enable the lenient mode
+    pub fn set_lenient(&mut self, lenient: bool) {
+        self.decoder.set_lenient(lenient);
     }
 
-    pub fn set_step_to(&mut self, to: Duration) {
-        self.stepto = Some(to);
+    /// Recover from corrupted or truncated frames instead of failing
+    ///
+    /// The corrupted data is dropped up to the next valid frame and the
+    /// callback receives a `Signal::Resync` event with the number of
+    /// dropped bytes
+    ///
+    /// Arguments
+    ///
+    /// * `resync` - true to enable the recovery mode
+    pub fn set_resync(&mut self, resync: bool) {
+        self.decoder.set_resync(resync);
     }
 
-    fn execute_event(&mut self, evt: &CallbackEvent) -> Result<(), Box<dyn Error>>
-    {
-        let mut handle = self.msg_handle.take();
-        if let Some(ref mut callback) = handle{
-            callback(evt,self)?;
-        }
-        self.msg_handle = handle;
-        Ok(())
+    /// Total number of bytes dropped by the recovery mode
+    pub fn dropped_bytes(&self) -> u64 {
+        self.decoder.dropped_bytes()
     }
 
-    pub fn step(&mut self) -> Result<(), Box<dyn Error>> {
-        // Poll Mio for events, blocking or timeout
-        let mut events = Events::with_capacity(MAX_EVT_CAPACITY);
-        let timeout = self.stepto;
-        self.get_poll()?.poll(&mut events, timeout)?;
-        // Process each event.
-        if events.is_empty()
-        {
-            let evt = CallbackEvent::create(None, None, None);
-            self.execute_event(&evt)?;
-        }
-        else
-        {
-            for event in events.iter() {
-                // We can use the token we previously provided to `register` to
-                // determine for which socket the event is.
-                let mut evt = CallbackEvent::create(None, Some(event), None);
-
-                match event.token() {
-                    SERVER => {
-                        let data = self.read()?;
-                        evt.msg = Some(&data);
-                        self.execute_event(&evt)?;       
-                    }
-                    token => {
-                        if let Some(fd) = self.io_fds.get(&token)
-                        {
-                            evt.fd = Some(*fd);
-                        }
-                        self.execute_event(&evt)?;
-                    }
-                }
-            }
-        }
-        Ok(())
+    /// Set the maximum payload size accepted from the tunnel
+    ///
+    /// Frames above the limit fail with `Error::PayloadTooLarge` unless
+    /// they are discarded (see `set_discard_oversized`).
+    /// The default limit is `codec::DEFAULT_MAX_PAYLOAD`
+    ///
+    /// Arguments
+    ///
+    /// * `size` - maximum payload size in bytes
+    pub fn set_max_payload(&mut self, size: u32) {
+        self.decoder.set_max_payload(size);
     }
-}
 
-impl<'a> Drop for Topic<'a> {
-    fn drop(&mut self) {
-        INFO!("Closing topic: {}", self.name);
-        let rq = Msg::create(MsgKind::ChannelUnsubscribeAll, 0, 0, Vec::new());
-        let evt = CallbackEvent::create(None, None, Some(&rq));
-        if let Err(error) = self.execute_event(&evt)
-        {
-            ERROR!("unable to properly drop topic [{}]: {}", self.name, error);
-        }
-        if let Err(error) = self.close() {
-            ERROR!("Unable to close topic [{}]: {}", self.name, error);
-        }
+    /// Discard the frames above the maximum payload size instead of
+    /// failing, the callback receives a `Signal::Discarded` event
+    ///
+    /// Arguments
+    ///
+    /// * `discard` - true to discard oversized frames
+    pub fn set_discard_oversized(&mut self, discard: bool) {
+        self.decoder.set_discard_oversized(discard);
     }
-}
 
-impl Msg {
-    /// Create new `Msg` object
+    /// Split the data messages above a threshold into fragments and
+    /// reassemble the fragments received from the clients
+    ///
+    /// The payload of every data message exchanged with the clients
+    /// starts with a flag telling whether it is a fragment, payloads
+    /// without flag are dropped, except the RPC envelopes (see `rpc`).
+    /// The handler only receives complete messages. Fragments out of
+    /// sequence, messages above the maximum size and messages not
+    /// completed before the timeout are dropped (see `fragment`)
     ///
     /// Arguments
     ///
-    /// * `kind` - message type
-    /// * `channel_id` - the channel id
-    /// * `client_id` - websocket client id
-    /// * `data` - raw data buffer
-    pub fn create(kind: MsgKind, channel_id: u16, client_id: u16, data: Vec<u8>) -> Self {
-        Self {
-            kind,
-            channel_id,
-            client_id,
-            size: data.len() as u32,
-            data: data,
-        }
+    /// * `policy` - the fragmentation policy
+    pub fn set_fragmentation(&mut self, policy: FragmentPolicy) {
+        self.fragmenter = Some(Fragmenter::new(policy));
     }
-}
 
-impl std::fmt::Display for Msg {
-    ///  Implement Display trait for Msg
+    /// Compress the data exchanged with the subscribers that opt in
+    ///
+    /// The compression is negotiated by each subscriber (see `compress`),
+    /// the handler only receives decompressed payloads
     ///
     /// Arguments
     ///
-    /// * `f` -input formatter
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        writeln!(f, "Antunnel message dump:")?;
-        writeln!(
-            f,
-            "Kind: {} - [{:#02x}]",
-            self.kind,
-            MsgKind::to_u8(&self.kind)
-        )?;
-        writeln!(
-            f,
-            "Channel ID: {} - {}",
-            self.channel_id,
-            format!("{:#02x?}", self.channel_id.to_be_bytes())
-        )?;
-        writeln!(
-            f,
-            "Client ID: {} - {}",
-            self.client_id,
-            format!("{:#02x?}", self.client_id.to_be_bytes())
-        )?;
-        writeln!(
-            f,
-            "Data size: {} - {}",
-            self.size,
-            format!("{:#02x?}", self.size.to_be_bytes())
-        )?;
-        writeln!(f, "Data : {}", format!("{:#02x?}", self.data))
+    /// * `policy` - the compression policy
+    pub fn set_compression(&mut self, policy: CompressionPolicy) {
+        self.compression = Some(policy);
     }
-}
 
-impl std::fmt::Display for MsgKind {
-    ///  Implement Display trait for Msgkind
+    /// Check if the topic is currently connected to the tunnel
+    ///
+    /// A topic waiting for the confirmation of a reconnection
+    /// is not connected yet
+    pub fn is_connected(&self) -> bool {
+        self.channel.is_some() && !self.handshake
+    }
+
+    /// Read a message from the socket
+    ///
+    fn read(&self) -> Result<Msg> {
+        let mut sock = self.channel.as_ref().ok_or(Error::NotConnected)?;
+        codec::read_msg_max(&mut sock, self.decoder.max_payload())
+    }
+
+    /// Read a chunk of the non-blocking socket and decode the
+    /// complete messages buffered so far
+    ///
+    /// The decoded messages are added to `msgs`, including those
+    /// decoded before an error
     ///
     /// Arguments
     ///
-    /// * `f` -input formatter
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        let s = match self {
-            MsgKind::ChannelOpen => 0x4,
-            MsgKind::ChannelOk => 0x0,
-            MsgKind::ChannelSubscribe => 0x2,
-            MsgKind::ChannelUnsubscribe => 0x3,
-            MsgKind::ChannelData => 0x6,
-            MsgKind::ChannelError => 0x1,
-            MsgKind::ChannelCtrl => 0x7,
-            MsgKind::ChannelClose => 0x5,
-            MsgKind::ChannelUnsubscribeAll => 0xA,
-            MsgKind::Unknown => 0xFF,
+    /// * `msgs` - the decoded messages
+    fn read_chunk(&mut self, msgs: &mut Vec<Msg>) -> Result<ReadState> {
+        let mut sock = self.channel.as_ref().ok_or(Error::NotConnected)?;
+        let state = match self.decoder.read_from(&mut sock, READ_BUFFER_SIZE) {
+            Ok(0) => ReadState::Closed,
+            Ok(_) => {
+                self.last_seen = Instant::now();
+                ReadState::Pending
+            }
+            Err(error) if error.kind() == ErrorKind::WouldBlock => ReadState::WouldBlock,
+            Err(error) if error.kind() == ErrorKind::Interrupted => ReadState::Pending,
+            Err(error) => return Err(error.into()),
         };
-        write!(f, "{:#02x}", s)
+        while let Some(msg) = self.decoder.decode()? {
+            msgs.push(msg);
+        }
+        Ok(state)
+    }
+
+    /// Queue a message to the socket
+    ///
+    /// The message is serialized into the outbound queue which is
+    /// flushed as much as possible without blocking, the rest is sent
+    /// when the socket becomes writable in `step`. The payload is
+    /// queued as is, a payload shared by several messages (e.g. a
+    /// broadcast) is never copied.
+    ///
+    /// When the queue is above the high-water mark, data messages are
+    /// rejected with `Error::Congested` until the queue is drained
+    /// (see `Signal::Drained`). Other messages are always queued.
+    ///
+    /// Arguments
+    ///
+    /// * `msg` - a message
+    pub fn write(&mut self, msg: &Msg) -> Result<()> {
+        self.write_encoded(msg, None)
+    }
+
+    /// Queue a message whose payload may be already encoded for
+    /// the compression of the client
+    ///
+    /// Arguments
+    ///
+    /// * `msg` - a message
+    /// * `encoded` - the parts of the encoded payload, `None` to
+    ///   encode the payload of the message
+    fn write_encoded(&mut self, msg: &Msg, encoded: Option<Vec<Bytes>>) -> Result<()> {
+        self.check_writable(msg.kind)?;
+        let parts = match encoded {
+            Some(parts) => parts,
+            None => self.encode_payload(msg)?,
+        };
+        let size: usize = parts.iter().map(|part| part.len()).sum();
+        let messages = match (msg.kind, self.fragmenter.as_mut()) {
+            (MsgKind::ChannelData, Some(fragmenter)) => fragmenter.split(parts)?,
+            _ => vec![parts],
+        };
+        self.write_frames(msg, messages, size)
+    }
+
+    /// Queue a message whose payload is sent as is, without the
+    /// compression and fragmentation flags (e.g. an RPC envelope)
+    ///
+    /// Arguments
+    ///
+    /// * `msg` - a message
+    fn write_unflagged(&mut self, msg: &Msg) -> Result<()> {
+        self.check_writable(msg.kind)?;
+        self.write_frames(msg, vec![vec![msg.data.clone()]], msg.data.len())
+    }
+
+    /// Check that a message can be queued
+    ///
+    /// Arguments
+    ///
+    /// * `kind` - the message kind
+    ///
+    /// # Errors
+    ///
+    /// * `Error::NotConnected` - the topic is not connected
+    /// * `Error::Congested` - a data message is written while the
+    ///   outbound queue is above the high-water mark
+    fn check_writable(&self, kind: MsgKind) -> Result<()> {
+        if !self.is_connected() {
+            return Err(Error::NotConnected);
+        }
+        if self.congested && kind == MsgKind::ChannelData {
+            return Err(Error::Congested);
+        }
+        Ok(())
+    }
+
+    /// Queue the frames of a message and flush the outbound queue
+    ///
+    /// Arguments
+    ///
+    /// * `msg` - the message giving the frame headers
+    /// * `messages` - the parts of the payload of each frame
+    /// * `size` - the payload size of the message
+    fn write_frames(&mut self, msg: &Msg, messages: Vec<Vec<Bytes>>, size: usize) -> Result<()> {
+        let frame_size = |parts: &Vec<Bytes>| parts.iter().map(|part| part.len()).sum::<usize>();
+        if let Some(len) = messages
+            .iter()
+            .map(frame_size)
+            .find(|len| *len > codec::MAX_FRAME_PAYLOAD)
+        {
+            return Err(Error::PayloadTooLarge {
+                size: len,
+                max: codec::MAX_FRAME_PAYLOAD,
+            });
+        }
+        for parts in messages.iter() {
+            self.enqueue(msg, parts);
+        }
+        if let MsgKind::ChannelData | MsgKind::ChannelCtrl = msg.kind {
+            if let Some(subscriber) = self.subscribers.get_mut(msg.client_id) {
+                subscriber.bytes_sent += size as u64;
+            }
+        }
+        self.flush()?;
+        if self.outbound_len >= self.high_water_mark {
+            WARN!(
+                "Outbound queue of topic {} is congested: {} bytes pending",
+                self.name,
+                self.outbound_len
+            );
+            self.congested = true;
+        }
+        Ok(())
+    }
+
+    /// Compression of the payload of a message, `None` if the
+    /// payload is exchanged without the compression flag
+    ///
+    /// Arguments
+    ///
+    /// * `kind` - the message kind
+    /// * `client_id` - the client id
+    fn payload_compression(&self, kind: MsgKind, client_id: u16) -> Option<Compression> {
+        if kind != MsgKind::ChannelData || self.compression.is_none() {
+            return None;
+        }
+        self.subscribers.get(client_id)?.compression
+    }
+
+    /// Encode the payload of a message for the compression of the client
+    ///
+    /// Return the parts of the payload
+    ///
+    /// Arguments
+    ///
+    /// * `msg` - the message
+    fn encode_payload(&self, msg: &Msg) -> Result<Vec<Bytes>> {
+        let compression = self.payload_compression(msg.kind, msg.client_id);
+        match (compression, self.compression.as_ref()) {
+            (Some(compression), Some(policy)) => {
+                compress::encode(compression, policy.threshold, &msg.data)
+            }
+            _ => Ok(vec![msg.data.clone()]),
+        }
+    }
+
+    /// Serialize a frame into the outbound queue
+    ///
+    /// Arguments
+    ///
+    /// * `msg` - the message giving the frame header
+    /// * `parts` - the parts of the frame payload
+    fn enqueue(&mut self, msg: &Msg, parts: &[Bytes]) {
+        let size: usize = parts.iter().map(|part| part.len()).sum();
+        self.outbound
+            .push_back(Bytes::copy_from_slice(&codec::frame_header(
+                msg,
+                size as u32,
+            )));
+        for part in parts.iter().filter(|part| !part.is_empty()) {
+            self.outbound.push_back(part.clone());
+        }
+        self.outbound.push_back(Bytes::from_static(&codec::TRAILER));
+        self.outbound_len += codec::HEADER_SIZE + size + codec::TRAILER_SIZE;
+    }
+
+    /// Write as much of the outbound queue as possible without blocking
+    ///
+    /// The socket is watched for writable readiness as long as
+    /// some data is pending
+    fn flush(&mut self) -> Result<()> {
+        let mut sock = self.channel.as_ref().ok_or(Error::NotConnected)?;
+        while !self.outbound.is_empty() {
+            let slices: Vec<IoSlice> = self
+                .outbound
+                .iter()
+                .take(MAX_IOV)
+                .map(|chunk| IoSlice::new(chunk))
+                .collect();
+            let mut sent = match sock.write_vectored(&slices) {
+                Ok(0) => return Err(Error::Closed),
+                Ok(n) => n,
+                Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+                Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error.into()),
+            };
+            self.outbound_len -= sent;
+            while sent > 0 {
+                let chunk = &mut self.outbound[0];
+                if sent < chunk.len() {
+                    chunk.advance(sent);
+                    break;
+                }
+                sent -= chunk.len();
+                let _ = self.outbound.pop_front();
+            }
+        }
+        let writable = !self.outbound.is_empty();
+        let token = self.server_token();
+        if writable != self.writable && self.io_fds.contains_key(&token) {
+            let fd = sock.as_raw_fd();
+            let interest = if writable {
+                Interest::READABLE | Interest::WRITABLE
+            } else {
+                Interest::READABLE
+            };
+            self.registry()?
+                .reregister(&mut SourceFd(&fd), token, interest)?;
+            let _ = self.io_fds.insert(token, (fd, interest));
+            self.writable = writable;
+        }
+        Ok(())
+    }
+
+    /// Flush the outbound queue, waiting for the socket to be
+    /// writable until a deadline
+    ///
+    /// Arguments
+    ///
+    /// * `deadline` - the time after which the rest of the queue is left
+    ///
+    /// # Errors
+    ///
+    /// * `Error::Timeout` - the queue is not flushed before the deadline
+    /// * any error returned by `flush`
+    fn flush_until(&mut self, deadline: Instant) -> Result<()> {
+        loop {
+            self.flush()?;
+            if self.outbound.is_empty() {
+                return Ok(());
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(Error::Timeout);
+            }
+            let fd = self
+                .channel
+                .as_ref()
+                .ok_or(Error::NotConnected)?
+                .as_raw_fd();
+            wait_writable(fd, left)?;
+        }
+    }
+
+    /// Number of bytes waiting in the outbound queue
+    pub fn pending_bytes(&self) -> usize {
+        self.outbound_len
+    }
+
+    /// Check if the outbound queue is above the high-water mark
+    ///
+    /// Data messages are rejected by `write` while the topic is congested
+    pub fn is_congested(&self) -> bool {
+        self.congested
+    }
+
+    /// Set the size of the outbound queue above which data messages are rejected
+    ///
+    /// Arguments
+    ///
+    /// * `size` - high-water mark in bytes
+    pub fn set_high_water_mark(&mut self, size: usize) {
+        self.high_water_mark = size;
+    }
+
+    /// Clients currently subscribed to the channel
+    ///
+    /// The subscribers are tracked from the `ChannelSubscribe` and
+    /// `ChannelUnsubscribe` messages, they are all unsubscribed when
+    /// the topic is closed or the tunnel connection is lost
+    pub fn subscribers(&self) -> &SubscriberMap<SubscriberData> {
+        &self.subscribers
+    }
+
+    /// Set the function creating the application data of each new
+    /// subscriber
+    ///
+    /// The data is created before the callback receives the
+    /// `Signal::Joined` event and is dropped with the subscriber, once
+    /// it has left. The subscribers have no data (`()`) by default
+    ///
+    /// Arguments
+    ///
+    /// * `init` - the function creating the data from the client id
+    pub fn set_subscriber_data<T: Any + Send>(
+        &mut self,
+        mut init: impl FnMut(u16) -> T + Send + 'static,
+    ) {
+        self.subscriber_init = Some(Box::new(move |client_id| Box::new(init(client_id))));
+    }
+
+    /// Get the application data of a subscriber
+    ///
+    /// Return `None` if the client is not subscribed or its data
+    /// is not of type `T`
+    ///
+    /// Arguments
+    ///
+    /// * `client_id` - the client id
+    pub fn subscriber_data<T: Any>(&self, client_id: u16) -> Option<&T> {
+        self.subscribers.get(client_id)?.data.downcast_ref()
+    }
+
+    /// Get a mutable reference to the application data of a subscriber
+    ///
+    /// Return `None` if the client is not subscribed or its data
+    /// is not of type `T`
+    ///
+    /// Arguments
+    ///
+    /// * `client_id` - the client id
+    pub fn subscriber_data_mut<T: Any>(&mut self, client_id: u16) -> Option<&mut T> {
+        self.subscribers.get_mut(client_id)?.data.downcast_mut()
+    }
+
+    /// Check if a client is subscribed to the channel
+    ///
+    /// Arguments
+    ///
+    /// * `client_id` - the client id
+    pub fn is_subscribed(&self, client_id: u16) -> bool {
+        self.subscribers.contains(client_id)
+    }
+
+    /// Send data to all the subscribers of the channel
+    ///
+    /// The payload is shared by all the messages, it is never copied.
+    /// It is compressed at most once per compression algorithm
+    ///
+    /// Arguments
+    ///
+    /// * `data` - the payload
+    ///
+    /// # Errors
+    ///
+    /// * `Error::Congested` - the outbound queue is full, the data is
+    ///   not sent to the remaining subscribers
+    /// * any error returned by `write`
+    pub fn broadcast(&mut self, data: impl Into<Bytes>) -> Result<()> {
+        let data = data.into();
+        let subscribers: Vec<u16> = self.subscribers.ids().collect();
+        let mut encoded: HashMap<Option<Compression>, Vec<Bytes>> = HashMap::new();
+        for client_id in subscribers {
+            let msg = Msg::create(MsgKind::ChannelData, 0, client_id, data.clone());
+            let compression = self.payload_compression(msg.kind, client_id);
+            let parts = match encoded.get(&compression) {
+                Some(parts) => parts.clone(),
+                None => {
+                    let parts = self.encode_payload(&msg)?;
+                    let _ = encoded.insert(compression, parts.clone());
+                    parts
+                }
+            };
+            self.write_encoded(&msg, Some(parts))?;
+        }
+        Ok(())
+    }
+
+    /// Send data to a subscriber of the channel
+    ///
+    /// Arguments
+    ///
+    /// * `client_id` - the client id
+    /// * `data` - the payload
+    ///
+    /// # Errors
+    ///
+    /// * `Error::NotSubscribed` - the client is not subscribed to the channel
+    /// * any error returned by `write`
+    pub fn send_to(&mut self, client_id: u16, data: impl Into<Bytes>) -> Result<()> {
+        if !self.subscribers.contains(client_id) {
+            return Err(Error::NotSubscribed(client_id));
+        }
+        let msg = Msg::create(MsgKind::ChannelData, 0, client_id, data);
+        self.write(&msg)
+    }
+
+    /// Pass a data message through the reassembly buffers
+    ///
+    /// Arguments
+    ///
+    /// * `msg` - the message
+    fn reassemble(&mut self, msg: &Msg) -> Reassembled {
+        let fragmenter = match self.fragmenter.as_mut() {
+            Some(fragmenter) if msg.kind == MsgKind::ChannelData => fragmenter,
+            _ => return Reassembled::Plain,
+        };
+        let reassembled = fragmenter.feed(msg.client_id, &msg.data, Instant::now());
+        if let Reassembled::Dropped(reason) = reassembled {
+            WARN!(
+                "Topic {}: message of client {} dropped: {}",
+                self.name,
+                msg.client_id,
+                reason
+            );
+        }
+        reassembled
+    }
+
+    /// Decompress the payload of a data message
+    ///
+    /// Return `None` if the payload of the message is passed as is
+    ///
+    /// Arguments
+    ///
+    /// * `msg` - the message
+    /// * `payload` - the reassembled payload of the message, if any
+    fn decode_payload(&self, msg: &Msg, payload: Option<Bytes>) -> Result<Option<Bytes>> {
+        let policy = match self.compression.as_ref() {
+            Some(policy) if self.payload_compression(msg.kind, msg.client_id).is_some() => policy,
+            _ => return Ok(payload),
+        };
+        let data = payload.as_ref().unwrap_or(&msg.data);
+        Ok(Some(compress::decode(data, policy.max_size)?))
+    }
+
+    /// Choose the compression requested by a subscriber
+    ///
+    /// Return the payload of the answer
+    ///
+    /// Arguments
+    ///
+    /// * `client_id` - the client id
+    /// * `names` - the requested algorithms
+    fn negotiate_compression(&mut self, client_id: u16, names: &[u8]) -> Bytes {
+        let chosen = match self.compression {
+            Some(_) => compress::negotiate(names),
+            None => None,
+        };
+        let chosen = match self.subscribers.get_mut(client_id) {
+            Some(subscriber) => {
+                subscriber.compression = chosen;
+                chosen
+            }
+            None => None,
+        };
+        Bytes::from_static(compress::negotiation_reply(chosen).as_bytes())
+    }
+
+    /// Register the handler of a control opcode
+    ///
+    /// The handler replaces the built-in handling of the opcode, if
+    /// any. The payload returned by the handler is sent back to the
+    /// client with the opcode of the answer (see `CtrlOp::reply`)
+    ///
+    /// Arguments
+    ///
+    /// * `op` - the opcode
+    /// * `handle` - the handler
+    pub fn on_ctrl(
+        &mut self,
+        op: CtrlOp,
+        handle: impl FnMut(&mut Topic, u16, Bytes) -> Result<Option<Bytes>> + Send + 'static,
+    ) {
+        let _ = self.ctrl_handles.insert(op, Box::new(handle));
+    }
+
+    /// Send a control message to a client
+    ///
+    /// Arguments
+    ///
+    /// * `client_id` - the client id
+    /// * `ctrl` - the control message
+    ///
+    /// # Errors
+    ///
+    /// * any error returned by `write`
+    pub fn send_ctrl(&mut self, client_id: u16, ctrl: &CtrlMsg) -> Result<()> {
+        let msg = Msg::create(MsgKind::ChannelCtrl, 0, client_id, ctrl.encode());
+        self.write(&msg)
+    }
+
+    /// Process a control message with the registered handlers or
+    /// the built-in commands
+    ///
+    /// Return false if the message must be passed to the callback
+    ///
+    /// Arguments
+    ///
+    /// * `msg` - the control message
+    fn handle_ctrl(&mut self, msg: &Msg) -> Result<bool> {
+        let ctrl = match CtrlMsg::decode(&msg.data) {
+            Some(ctrl) => ctrl,
+            None => return Ok(false),
+        };
+        let reply = if let Some(mut handle) = self.ctrl_handles.remove(&ctrl.op) {
+            let result = handle(self, msg.client_id, ctrl.payload);
+            // a handler registered from within the handler replaces the current one
+            let _ = self.ctrl_handles.entry(ctrl.op).or_insert(handle);
+            match result? {
+                Some(reply) => reply,
+                None => return Ok(true),
+            }
+        } else {
+            match ctrl.op {
+                CtrlOp::Ping => ctrl.payload,
+                // answer of the tunnel service to the heartbeat
+                CtrlOp::Pong if msg.client_id == 0 => return Ok(true),
+                CtrlOp::Stats => self.client_stats(msg.client_id),
+                CtrlOp::Info => self.info(),
+                CtrlOp::Compress => self.negotiate_compression(msg.client_id, &ctrl.payload),
+                _ => return Ok(false),
+            }
+        };
+        let reply = CtrlMsg::create(ctrl.op.reply(), reply);
+        self.send_ctrl(msg.client_id, &reply)?;
+        Ok(true)
+    }
+
+    /// Statistics of a subscriber, empty if the client is not subscribed
+    ///
+    /// Arguments
+    ///
+    /// * `client_id` - the client id
+    fn client_stats(&self, client_id: u16) -> Bytes {
+        let subscriber = match self.subscribers.get(client_id) {
+            Some(subscriber) => subscriber,
+            None => return Bytes::new(),
+        };
+        let compression = compress::negotiation_reply(subscriber.compression);
+        ctrl::encode_fields(&[
+            ("client_id", client_id.to_string()),
+            ("bytes_sent", subscriber.bytes_sent.to_string()),
+            ("bytes_received", subscriber.bytes_received.to_string()),
+            (
+                "joined_ms",
+                subscriber.joined_at.elapsed().as_millis().to_string(),
+            ),
+            (
+                "idle_ms",
+                subscriber.last_activity.elapsed().as_millis().to_string(),
+            ),
+            ("compression", String::from(compression)),
+        ])
+    }
+
+    /// Information of the topic
+    fn info(&self) -> Bytes {
+        let compression: Vec<&str> = match self.compression {
+            Some(_) => Compression::supported()
+                .iter()
+                .map(|compression| compression.name())
+                .collect(),
+            None => Vec::new(),
+        };
+        let fragmentation = match self.fragmenter.as_ref() {
+            Some(fragmenter) => fragmenter.policy().threshold.to_string(),
+            None => String::from("none"),
+        };
+        ctrl::encode_fields(&[
+            ("name", self.name.clone()),
+            ("version", String::from(env!("CARGO_PKG_VERSION"))),
+            ("subscribers", self.subscribers.len().to_string()),
+            ("compression", compression.join(",")),
+            ("fragmentation", fragmentation),
+        ])
+    }
+
+    /// Update the subscribers from a message of the tunnel
+    ///
+    /// The callback receives a `Signal::Joined` event once a new
+    /// subscriber is added and a `Signal::Left` event before a
+    /// subscriber is removed
+    ///
+    /// Arguments
+    ///
+    /// * `msg` - the message
+    fn track_subscriber(&mut self, msg: &Msg) -> Result<()> {
+        match msg.kind {
+            MsgKind::ChannelSubscribe if !self.subscribers.contains(msg.client_id) => {
+                let data: SubscriberData = match self.subscriber_init.as_mut() {
+                    Some(init) => init(msg.client_id),
+                    None => Box::new(()),
+                };
+                let _ = self.subscribers.insert(msg.client_id, data);
+                if let Some(names) = compress::subscribe_request(&msg.data) {
+                    let reply = self.negotiate_compression(msg.client_id, names);
+                    self.send_ctrl(msg.client_id, &CtrlMsg::create(CtrlOp::Compress, reply))?;
+                }
+                let evt = CallbackEvent::signal(Signal::Joined(msg.client_id));
+                self.execute_event(&evt)?;
+            }
+            MsgKind::ChannelUnsubscribe if self.subscribers.contains(msg.client_id) => {
+                let evt = CallbackEvent::signal(Signal::Left(msg.client_id));
+                self.execute_event(&evt)?;
+                let _ = self.subscribers.remove(msg.client_id);
+                if let Some(fragmenter) = self.fragmenter.as_mut() {
+                    fragmenter.remove(msg.client_id);
+                }
+            }
+            MsgKind::ChannelUnsubscribeAll => self.leave_all()?,
+            MsgKind::ChannelData | MsgKind::ChannelCtrl => {
+                if let Some(subscriber) = self.subscribers.get_mut(msg.client_id) {
+                    subscriber.last_activity = Instant::now();
+                    subscriber.bytes_received += msg.data.len() as u64;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Remove all the subscribers, the callback receives a
+    /// `Signal::Left` event for each of them
+    ///
+    fn leave_all(&mut self) -> Result<()> {
+        let subscribers: Vec<u16> = self.subscribers.ids().collect();
+        for client_id in subscribers {
+            let evt = CallbackEvent::signal(Signal::Left(client_id));
+            self.execute_event(&evt)?;
+            let _ = self.subscribers.remove(client_id);
+        }
+        if let Some(fragmenter) = self.fragmenter.as_mut() {
+            fragmenter.clear();
+        }
+        Ok(())
+    }
+
+    /// Close the topic
+    ///
+    /// The messages queued by the `TopicSender`s are written, then
+    /// the callback receives the `ChannelUnsubscribeAll` message, a
+    /// `Signal::Left` event for each subscriber and a `Signal::Closing`
+    /// event, then the subscribers are unsubscribed and the channel
+    /// is closed. The topic is not closed again when dropped.
+    ///
+    /// # Errors
+    ///
+    /// * the first error returned by the callback or by the tunnel
+    ///   connection, the closing sequence is completed anyway
+    pub fn close(&mut self) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+        INFO!("Closing topic: {}", self.name);
+        // the messages queued by the senders are written first
+        self.drain_inbox();
+        let rq = Msg::create(MsgKind::ChannelUnsubscribeAll, 0, 0, Vec::new());
+        let evt = CallbackEvent::create(None, None, Some(&rq));
+        let mut result = self.execute_event(&evt);
+        // the subscribers are unsubscribed from the tunnel by `close_channel`
+        let subscribers: Vec<u16> = self.subscribers.ids().collect();
+        for client_id in subscribers {
+            let evt = CallbackEvent::signal(Signal::Left(client_id));
+            result = result.and(self.execute_event(&evt));
+        }
+        let evt = CallbackEvent::signal(Signal::Closing);
+        result = result.and(self.execute_event(&evt));
+        if self.is_connected() {
+            result = result.and(self.close_channel());
+        }
+        self.retry = None;
+        self.disconnect();
+        result
+    }
+
+    /// Close the tunnel
+    ///
+    /// The subscribers are unsubscribed and the pending outbound
+    /// messages are sent before closing the socket. The socket stays
+    /// non-blocking, the messages not sent within `CLOSE_FLUSH_TIMEOUT`
+    /// are dropped
+    fn close_channel(&mut self) -> Result<()> {
+        INFO!("Closing the channel: {}", self.name);
+        let rq = Msg::create(MsgKind::ChannelClose, 0, 0, Bytes::new());
+        let subscribers: Vec<u16> = self.subscribers.ids().collect();
+        self.subscribers.clear();
+        for client_id in subscribers {
+            let msg = Msg::create(MsgKind::ChannelUnsubscribe, 0, client_id, Bytes::new());
+            if let Err(error) = self.write(&msg) {
+                WARN!("Unable to unsubscribe client {}: {}", client_id, error);
+            }
+        }
+        if let Err(error) = self.write(&rq) {
+            WARN!("Unable to write close message to tunnel server {}", error);
+        }
+        let deadline = Instant::now() + CLOSE_FLUSH_TIMEOUT;
+        if let Err(error) = self.flush_until(deadline) {
+            WARN!(
+                "Topic {}: {} pending bytes dropped on close: {}",
+                self.name,
+                self.outbound_len,
+                error
+            );
+        }
+        self.channel
+            .as_ref()
+            .ok_or(Error::NotConnected)?
+            .shutdown(Shutdown::Both)?;
+        Ok(())
+    }
+
+    /// Set the callback called on each event of the topic
+    ///
+    /// The callback owns its captured state, so that the topic
+    /// can be stored in a struct or moved to another thread
+    ///
+    /// Arguments
+    ///
+    /// * `callback` - the message handle
+    pub fn on_message(
+        &mut self,
+        callback: impl FnMut(&CallbackEvent, &mut Topic) -> Result<()> + Send + 'static,
+    ) {
+        self.msg_handle = Some(Box::new(callback));
+    }
+
+    /// Set the handler called on each event of the topic
+    ///
+    /// Arguments
+    ///
+    /// * `handler` - the topic handler
+    pub fn set_handler(&mut self, handler: impl TopicHandler + 'static) {
+        self.msg_handle = Some(Box::new(handler));
+    }
+
+    /// Get the registry used to register the topic sockets
+    ///
+    /// A standalone topic creates its own poll object on first use,
+    /// a topic hosted by a `Reactor` uses the registry of the reactor
+    fn registry(&mut self) -> Result<&Registry> {
+        let registry = match self.registry.take() {
+            Some(registry) => registry,
+            None => {
+                let poll = Poll::new()?;
+                let registry = poll.registry().try_clone()?;
+                self.poll = Some(poll);
+                registry
+            }
+        };
+        Ok(self.registry.insert(registry))
+    }
+
+    /// Token of the tunnel socket
+    fn server_token(&self) -> Token {
+        Token(self.token_base)
+    }
+
+    /// Move all the registered sockets to the registry of a reactor
+    ///
+    /// Arguments
+    ///
+    /// * `registry` - the registry of the reactor
+    /// * `token_base` - the first token reserved to the topic
+    /// * `waker` - the waker of the reactor
+    fn attach(&mut self, registry: Registry, token_base: usize, waker: Arc<Waker>) -> Result<()> {
+        let fds: Vec<(Token, (RawFd, Interest))> = self.io_fds.drain().collect();
+        for (token, (fd, interest)) in fds {
+            if let Some(old) = self.registry.as_ref() {
+                old.deregister(&mut SourceFd(&fd))?;
+            }
+            let token = Token(token.0 - self.token_base + token_base);
+            registry.register(&mut SourceFd(&fd), token, interest)?;
+            let _ = self.io_fds.insert(token, (fd, interest));
+        }
+        self.registry = Some(registry);
+        self.poll = None;
+        self.waker = Some(waker);
+        self.token_base = token_base;
+        Ok(())
+    }
+
+    /// Register a file descriptor to polling, the callback
+    /// receives an event with the `fd` set when it is ready
+    ///
+    /// Arguments
+    ///
+    /// * `fd` - the file descriptor
+    /// * `interest` - the readiness to watch
+    pub fn register_io(&mut self, fd: RawFd, interest: IOInterest) -> Result<()> {
+        if self.n_token >= 1 << TOPIC_TOKEN_BITS {
+            return Err(Error::Other(format!(
+                "Too many file descriptors registered to topic {}",
+                self.name
+            )));
+        }
+        // add socket to polling
+        let token = Token(self.token_base + self.n_token);
+        self.registry()?
+            .register(&mut SourceFd(&fd), token, interest)?;
+        // register the handle
+        let _ = self.io_fds.insert(token, (fd, interest));
+        self.n_token += 1;
+        Ok(())
+    }
+
+    /// Remove a file descriptor from polling
+    ///
+    /// Arguments
+    ///
+    /// * `fd` - the file descriptor
+    pub fn unregister_io(&mut self, fd: RawFd) -> Result<()> {
+        self.registry()?.deregister(&mut SourceFd(&fd))?;
+        // remove by value
+        self.io_fds.retain(|_, v| v.0 != fd);
+        Ok(())
+    }
+
+    /// Set the time without activity after which the callback
+    /// receives an empty event
+    ///
+    /// Arguments
+    ///
+    /// * `to` - the timeout
+    pub fn set_step_to(&mut self, to: Duration) {
+        self.stepto = Some(to);
+    }
+
+    fn execute_event(&mut self, evt: &CallbackEvent) -> Result<()> {
+        let mut handle = self.msg_handle.take();
+        let result = match handle {
+            Some(ref mut handler) => handler.on_event(evt, self),
+            None => Ok(()),
+        };
+        // a callback set from within the callback replaces the current one
+        if self.msg_handle.is_none() {
+            self.msg_handle = handle;
+        }
+        result
+    }
+
+    /// Add a timer to the topic
+    ///
+    /// The callback receives a `Signal::Timer` event each time
+    /// the timer expires
+    ///
+    /// Arguments
+    ///
+    /// * `schedule` - when the timer fires
+    pub fn add_timer(&mut self, schedule: Schedule) -> TimerId {
+        self.timers.add(schedule)
+    }
+
+    /// Remove a timer from the topic
+    ///
+    /// Return false if the timer does not exist or has already
+    /// fired (one-shot timer)
+    ///
+    /// Arguments
+    ///
+    /// * `id` - the timer identifier
+    pub fn cancel_timer(&mut self, id: TimerId) -> bool {
+        self.timers.cancel(id)
+    }
+
+    /// Time from which the topic is idle: the shortest step timeout
+    /// elapsed since the last activity or idle event
+    ///
+    /// The timers, heartbeat and reconnection attempts are not an
+    /// activity of the topic
+    ///
+    /// Arguments
+    ///
+    /// * `stepto` - the step timeout of the reactor hosting the topic
+    fn idle_deadline(&self, stepto: Option<Duration>) -> Option<Instant> {
+        let to = self.stepto.into_iter().chain(stepto).min()?;
+        Some(self.last_active + to)
+    }
+
+    /// Time left until the next timed action of the topic (idle
+    /// event, timer, heartbeat, reassembly timeout or reconnection
+    /// attempt)
+    ///
+    /// Arguments
+    ///
+    /// * `stepto` - the step timeout of the reactor hosting the topic
+    fn timeout(&self, stepto: Option<Duration>) -> Option<Duration> {
+        let deadline = self
+            .idle_deadline(stepto)
+            .into_iter()
+            .chain(self.retry.map(|(deadline, _)| deadline))
+            .chain(self.heartbeat_deadline())
+            .chain(self.timers.next_deadline())
+            .chain(self.fragmenter.as_ref().and_then(Fragmenter::next_deadline))
+            .min()?;
+        Some(deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// Wait for events on the topic sockets and process them
+    ///
+    /// The callback receives a `Signal::Timer` event for each
+    /// expired timer, and an empty event if nothing happens during
+    /// the step timeout (see `set_step_to`).
+    ///
+    /// # Errors
+    ///
+    /// * `Error::Other` - the topic is hosted by a `Reactor`, use
+    ///   `Reactor::step` instead
+    /// * any error returned by the callback or by the tunnel connection
+    pub fn step(&mut self) -> Result<()> {
+        // Poll Mio for events, blocking or timeout
+        let mut events = Events::with_capacity(MAX_EVT_CAPACITY);
+        let timeout = self.timeout(None);
+        let _ = self.registry()?;
+        self.poll
+            .as_mut()
+            .ok_or_else(|| Error::Other(String::from("Topic is hosted by a reactor")))?
+            .poll(&mut events, timeout)?;
+        // Process each event.
+        for event in events.iter().filter(|event| event.token() != WAKER) {
+            self.handle_event(event)?;
+        }
+        self.tick(None)
+    }
+
+    /// Step the topic until the shutdown is requested with a
+    /// `ShutdownHandle`, then close it (see `close`)
+    ///
+    /// # Errors
+    ///
+    /// * any error returned by `step`, the topic is closed before
+    ///   the error is returned
+    /// * any error returned by `close`
+    pub fn run(&mut self) -> Result<()> {
+        let _ = self.waker()?;
+        while !self.is_shutdown() {
+            if let Err(error) = self.step() {
+                if let Err(error) = self.close() {
+                    ERROR!("Unable to close topic [{}]: {}", self.name, error);
+                }
+                return Err(error);
+            }
+        }
+        self.close()
+    }
+
+    /// Get a handle stopping `run` from another thread or a signal handler
+    ///
+    /// For a topic hosted by a `Reactor`, the topic is closed and
+    /// removed from the reactor by `Reactor::step`. The handles
+    /// should be created once the topic is added to the reactor.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::Io` - unable to create the waker of the poll object
+    pub fn shutdown_handle(&mut self) -> Result<ShutdownHandle> {
+        Ok(ShutdownHandle::create(self.waker()?, self.shutdown.clone()))
+    }
+
+    /// Check if the shutdown of the topic has been requested
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    /// Get a handle sending messages to the topic from other threads
+    ///
+    /// The messages are written by `step`. As for `shutdown_handle`,
+    /// the senders of a topic hosted by a `Reactor` should be created
+    /// once the topic is added to the reactor.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::Io` - unable to create the waker of the poll object
+    pub fn sender(&mut self) -> Result<TopicSender> {
+        let waker = self.waker()?;
+        let (queue, _) = self
+            .inbox
+            .get_or_insert_with(|| mpsc::sync_channel(SENDER_QUEUE_SIZE));
+        Ok(TopicSender::create(queue.clone(), waker))
+    }
+
+    /// Write the messages queued by the `TopicSender`s
+    ///
+    /// A failed write is logged and the message is dropped
+    fn drain_inbox(&mut self) {
+        let commands: Vec<Command> = match self.inbox.as_ref() {
+            Some((_, inbox)) => inbox.try_iter().take(SENDER_QUEUE_SIZE).collect(),
+            None => return,
+        };
+        if commands.len() == SENDER_QUEUE_SIZE {
+            // process the rest in the next step
+            if let Some(waker) = self.waker.as_ref() {
+                let _ = waker.wake();
+            }
+        }
+        for command in commands {
+            let result = match command {
+                Command::Write(msg) => self.write(&msg),
+                Command::Broadcast(data) => self.broadcast(data),
+                Command::SendTo(client_id, data) => self.send_to(client_id, data),
+            };
+            if let Err(error) = result {
+                WARN!(
+                    "Topic {}: unable to send queued message: {}",
+                    self.name,
+                    error
+                );
+            }
+        }
+    }
+
+    /// Get the waker of the poll object, a standalone topic
+    /// creates it on first use
+    ///
+    fn waker(&mut self) -> Result<Arc<Waker>> {
+        if let Some(waker) = self.waker.as_ref() {
+            return Ok(waker.clone());
+        }
+        let waker = Arc::new(Waker::new(self.registry()?, WAKER)?);
+        self.waker = Some(waker.clone());
+        Ok(waker)
+    }
+
+    /// Process the queued messages and the timed actions of the
+    /// topic after the events
+    ///
+    /// The callback receives an empty event if the step timeout has
+    /// elapsed since the last activity of the topic
+    ///
+    /// Arguments
+    ///
+    /// * `stepto` - the step timeout of the reactor hosting the topic
+    fn tick(&mut self, stepto: Option<Duration>) -> Result<()> {
+        self.drain_inbox();
+        let now = Instant::now();
+        let expired = self.timers.expired(now);
+        for id in expired.iter() {
+            // a timer may be cancelled by a previous callback
+            if self.timers.fire(*id, now) {
+                let evt = CallbackEvent::signal(Signal::Timer(*id));
+                self.execute_event(&evt)?;
+            }
+        }
+        if self
+            .idle_deadline(stepto)
+            .is_some_and(|deadline| now >= deadline)
+        {
+            self.last_active = now;
+            let evt = CallbackEvent::create(None, None, None);
+            self.execute_event(&evt)?;
+        }
+        if let Some(fragmenter) = self.fragmenter.as_mut() {
+            for client_id in fragmenter.expire(now) {
+                WARN!(
+                    "Topic {}: message of client {} dropped: reassembly timed out",
+                    self.name,
+                    client_id
+                );
+            }
+        }
+        self.check_heartbeat(now)?;
+        self.try_reconnect()
+    }
+
+    /// Process an event on one of the topic sockets
+    ///
+    /// Arguments
+    ///
+    /// * `event` - the poll event
+    fn handle_event(&mut self, event: &Event) -> Result<()> {
+        // We can use the token we previously provided to `register` to
+        // determine for which socket the event is.
+        let mut evt = CallbackEvent::create(None, Some(event), None);
+        if event.token() != self.server_token() {
+            if let Some((fd, _)) = self.io_fds.get(&event.token()) {
+                evt.fd = Some(*fd);
+            }
+            self.last_active = Instant::now();
+            return self.execute_event(&evt);
+        }
+        if event.is_writable() {
+            if let Err(error) = self.flush() {
+                return self.connection_lost(error);
+            }
+            if self.congested && self.outbound.is_empty() {
+                self.congested = false;
+                let evt = CallbackEvent::signal(Signal::Drained);
+                self.execute_event(&evt)?;
+            }
+        }
+        if !event.is_readable() && !event.is_read_closed() {
+            return Ok(());
+        }
+        // the readiness is edge-triggered, the socket is read until it
+        // would block. A chunk may carry zero, one or many messages, they
+        // are dispatched before the next chunk is read
+        while self.channel.is_some() {
+            let dropped = self.decoder.dropped_bytes();
+            let discarded = self.decoder.discarded_frames();
+            let mut msgs = Vec::new();
+            let state = self.read_chunk(&mut msgs);
+            self.report_dropped(dropped, discarded)?;
+            self.dispatch(event, msgs)?;
+            if self.channel.is_none() {
+                // closed or dropped by the callback
+                break;
+            }
+            match state {
+                Ok(ReadState::Pending) => {}
+                Ok(ReadState::WouldBlock) => break,
+                Ok(ReadState::Closed) => return self.connection_lost(Error::Closed),
+                Err(error) => return self.connection_lost(error),
+            }
+        }
+        Ok(())
+    }
+
+    /// Notify the callback of the data dropped by the decoder
+    ///
+    /// Arguments
+    ///
+    /// * `dropped` - the number of bytes dropped before the last read
+    /// * `discarded` - the number of frames discarded before the last read
+    fn report_dropped(&mut self, dropped: u64, discarded: u64) -> Result<()> {
+        let dropped = self.decoder.dropped_bytes() - dropped;
+        if dropped > 0 {
+            WARN!(
+                "Topic {}: {} corrupted bytes dropped from tunnel stream",
+                self.name,
+                dropped
+            );
+            let evt = CallbackEvent::signal(Signal::Resync(dropped));
+            self.execute_event(&evt)?;
+        }
+        let discarded = self.decoder.discarded_frames() - discarded;
+        if discarded > 0 {
+            WARN!(
+                "Topic {}: {} oversized frames discarded",
+                self.name,
+                discarded
+            );
+            let evt = CallbackEvent::signal(Signal::Discarded(discarded));
+            self.execute_event(&evt)?;
+        }
+        Ok(())
+    }
+
+    /// Pass the messages read from the tunnel to the callback
+    ///
+    /// The first message of a reconnection attempt is the response
+    /// to the channel opening
+    ///
+    /// Arguments
+    ///
+    /// * `event` - the poll event
+    /// * `msgs` - the messages
+    fn dispatch(&mut self, event: &Event, mut msgs: Vec<Msg>) -> Result<()> {
+        if self.handshake && !msgs.is_empty() && !self.handshake_done(msgs.remove(0))? {
+            return Ok(());
+        }
+        for msg in msgs.iter() {
+            // the heartbeat of the tunnel service is not an activity
+            if msg.kind != MsgKind::ChannelCtrl || msg.client_id != 0 {
+                self.last_active = Instant::now();
+            }
+            self.track_subscriber(msg)?;
+            if msg.kind == MsgKind::ChannelCtrl && self.handle_ctrl(msg)? {
+                continue;
+            }
+            let payload = if msg.kind == MsgKind::ChannelData && Envelope::is_envelope(&msg.data) {
+                // the RPC envelopes are exchanged without payload flags
+                None
+            } else {
+                let payload = match self.reassemble(msg) {
+                    Reassembled::Plain => None,
+                    Reassembled::Complete(data) => Some(data),
+                    Reassembled::Partial | Reassembled::Dropped(_) => continue,
+                };
+                match self.decode_payload(msg, payload) {
+                    Ok(payload) => payload,
+                    Err(error) => {
+                        WARN!(
+                            "Topic {}: message of client {} dropped: {}",
+                            self.name,
+                            msg.client_id,
+                            error
+                        );
+                        continue;
+                    }
+                }
+            };
+            match payload {
+                None => {
+                    let evt = CallbackEvent::create(None, Some(event), Some(msg));
+                    self.execute_event(&evt)?;
+                }
+                Some(data) => {
+                    let msg = Msg::create(msg.kind, msg.channel_id, msg.client_id, data);
+                    let evt = CallbackEvent::create(None, Some(event), Some(&msg));
+                    self.execute_event(&evt)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Wait until a socket is writable or a timeout elapses
+///
+/// Arguments
+///
+/// * `fd` - the socket
+/// * `timeout` - the maximum waiting time
+fn wait_writable(fd: RawFd, timeout: Duration) -> Result<()> {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLOUT,
+        revents: 0,
+    };
+    let timeout = timeout.as_millis().clamp(1, i32::MAX as u128) as i32;
+    if unsafe { libc::poll(&mut pollfd, 1, timeout) } < 0 {
+        let error = std::io::Error::last_os_error();
+        if error.kind() != ErrorKind::Interrupted {
+            return Err(error.into());
+        }
+    }
+    Ok(())
+}
+
+impl Drop for Topic {
+    fn drop(&mut self) {
+        if let Err(error) = self.close() {
+            ERROR!("Unable to close topic [{}]: {}", self.name, error);
+        }
+        if let Some(registry) = self.registry.as_ref() {
+            for (fd, _) in self.io_fds.values() {
+                let _ = registry.deregister(&mut SourceFd(fd));
+            }
+        }
+    }
+}
+
+impl Msg {
+    /// Create new `Msg` object
+    ///
+    /// Arguments
+    ///
+    /// * `kind` - message type
+    /// * `channel_id` - the channel id
+    /// * `client_id` - websocket client id
+    /// * `data` - raw data buffer, a `Vec<u8>` or a shared `Bytes` buffer
+    pub fn create(kind: MsgKind, channel_id: u16, client_id: u16, data: impl Into<Bytes>) -> Self {
+        let data = data.into();
+        Self {
+            kind,
+            channel_id,
+            client_id,
+            size: data.len() as u32,
+            data,
+        }
+    }
+
+    /// Get the error text carried by a `ChannelError` message
+    ///
+    /// Return `None` if the message is not an error or has no payload
+    pub fn error_text(&self) -> Option<String> {
+        match self.kind {
+            MsgKind::ChannelError if !self.data.is_empty() => {
+                Some(String::from_utf8_lossy(&self.data).into_owned())
+            }
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Msg {
+    ///  Implement Display trait for Msg
+    ///
+    /// Arguments
+    ///
+    /// * `f` -input formatter
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "Antunnel message dump:")?;
+        writeln!(f, "Kind: {} - [{:#02x}]", self.kind, u8::from(self.kind))?;
+        writeln!(
+            f,
+            "Channel ID: {} - {:#02x?}",
+            self.channel_id,
+            self.channel_id.to_be_bytes()
+        )?;
+        writeln!(
+            f,
+            "Client ID: {} - {:#02x?}",
+            self.client_id,
+            self.client_id.to_be_bytes()
+        )?;
+        writeln!(
+            f,
+            "Data size: {} - {:#02x?}",
+            self.size,
+            self.size.to_be_bytes()
+        )?;
+        writeln!(f, "Data : {:#02x?}", self.data)
+    }
+}
+
+impl std::fmt::Display for MsgKind {
+    ///  Implement Display trait for Msgkind
+    ///
+    /// Arguments
+    ///
+    /// * `f` -input formatter
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MsgKind::Unknown(value) => write!(f, "Unknown({:#02x})", value),
+            kind => write!(f, "{:?}", kind),
+        }
     }
 }
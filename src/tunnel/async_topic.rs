@@ -0,0 +1,210 @@
+//! # //! Asynchronous (tokio) tunnel client
+//!
+//! `AsyncTopic` opens a channel on the tunnel socket and exposes
+//! the received messages as a `Stream` and an async `send` method.
+//! It uses the same framing as `Topic` (see `codec`).
+//!
+//! This module is only available with the `async` feature.
+//!
+//! **Author**: "Dany LE"
+//!
+use super::codec::{self, Decoder};
+use super::{Bytes, Msg, MsgKind};
+use crate::error::{Error, Result};
+use crate::utils::{LogLevel, LOG};
+use crate::{ERROR, INFO};
+use bytes::Buf;
+use futures_core::Stream;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::AsyncWriteExt;
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::UnixStream;
+use tokio_util::io::poll_read_buf;
+
+const READ_BUFFER_SIZE: usize = 4096;
+
+/// Receiving half of an `AsyncTopic`
+///
+/// Stream of the messages sent by the tunnel
+pub struct MsgReader {
+    stream: OwnedReadHalf,
+    decoder: Decoder,
+    eof: bool,
+}
+
+/// Sending half of an `AsyncTopic`
+pub struct MsgWriter {
+    stream: OwnedWriteHalf,
+}
+
+/// Asynchronous topic
+///
+/// Stream of the messages sent by the tunnel to the topic
+pub struct AsyncTopic {
+    pub name: String,
+    reader: MsgReader,
+    writer: MsgWriter,
+}
+
+impl AsyncTopic {
+    /// Connect to the tunnel socket and open the channel of the topic
+    ///
+    /// Arguments
+    ///
+    /// * `name` - a topic name
+    /// * `socket_file` - a path to tunnel socket
+    ///
+    /// # Errors
+    ///
+    /// * `Error::ChannelRefused` - the tunnel service does not confirm the
+    ///   channel opening, the error holds the response message
+    /// * `Error::Closed` - the tunnel closes the connection before responding
+    /// * `Error::Io` - unable to connect to the tunnel socket
+    pub async fn open(name: &str, socket_file: &str) -> Result<Self> {
+        INFO!("Open unix domain socket: {}", socket_file);
+        let (reader, writer) = UnixStream::connect(socket_file).await?.into_split();
+        let mut topic = AsyncTopic {
+            name: String::from(name),
+            reader: MsgReader {
+                stream: reader,
+                decoder: Decoder::new(),
+                eof: false,
+            },
+            writer: MsgWriter { stream: writer },
+        };
+        let rq = Msg::create(
+            MsgKind::ChannelOpen,
+            0,
+            0,
+            Bytes::copy_from_slice(name.as_bytes()),
+        );
+        topic.send(rq).await?;
+        INFO!("Wait for comfirm channel opening from: {}", socket_file);
+        let response = topic.recv().await.ok_or(Error::Closed)??;
+        if !matches!(response.kind, MsgKind::ChannelOk) {
+            match response.error_text() {
+                Some(text) => ERROR!("Channel {} is not created: {}", name, text),
+                None => ERROR!(
+                    "Channel {} is not created. Tunnel service responds with msg of type {}",
+                    name,
+                    response.kind
+                ),
+            }
+            return Err(Error::ChannelRefused(response));
+        }
+        INFO!("Channel {} opened sucessfully", name);
+        Ok(topic)
+    }
+
+    /// Send a message to the tunnel
+    ///
+    /// Arguments
+    ///
+    /// * `msg` - a message
+    pub async fn send(&mut self, msg: Msg) -> Result<()> {
+        self.writer.send(msg).await
+    }
+
+    /// Receive the next message from the tunnel
+    ///
+    /// Return `None` when the tunnel closes the connection or
+    /// after an error has been returned
+    pub async fn recv(&mut self) -> Option<Result<Msg>> {
+        self.reader.recv().await
+    }
+
+    /// Get the frame decoder, e.g. to set the payload size limit
+    pub fn decoder_mut(&mut self) -> &mut Decoder {
+        &mut self.reader.decoder
+    }
+
+    /// Close the channel and the connection to the tunnel
+    ///
+    pub async fn close(mut self) -> Result<()> {
+        INFO!("Closing the channel: {}", self.name);
+        let rq = Msg::create(MsgKind::ChannelClose, 0, 0, Bytes::new());
+        self.writer.send(rq).await?;
+        self.writer.stream.shutdown().await?;
+        Ok(())
+    }
+
+    /// Split the topic into a receiving and a sending half
+    ///
+    /// The halves can be used from different tasks. The channel
+    /// is not closed when they are dropped.
+    pub fn split(self) -> (MsgReader, MsgWriter) {
+        (self.reader, self.writer)
+    }
+}
+
+impl MsgReader {
+    /// Receive the next message from the tunnel
+    ///
+    /// Return `None` when the tunnel closes the connection or
+    /// after an error has been returned
+    pub async fn recv(&mut self) -> Option<Result<Msg>> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+
+    /// End the stream after a decoding or reading error, the
+    /// buffered data can not be decoded any more
+    ///
+    /// Arguments
+    ///
+    /// * `error` - the error
+    fn fail(&mut self, error: Error) -> Error {
+        self.decoder.clear();
+        self.eof = true;
+        error
+    }
+}
+
+impl MsgWriter {
+    /// Send a message to the tunnel
+    ///
+    /// The payload is written as is, without being copied
+    ///
+    /// Arguments
+    ///
+    /// * `msg` - a message
+    pub async fn send(&mut self, msg: Msg) -> Result<()> {
+        let header = codec::encode_header(&msg)?;
+        let mut frame = (&header[..]).chain(msg.data).chain(&codec::TRAILER[..]);
+        self.stream.write_all_buf(&mut frame).await?;
+        Ok(())
+    }
+}
+
+impl Stream for MsgReader {
+    type Item = Result<Msg>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            match this.decoder.decode() {
+                Ok(Some(msg)) => return Poll::Ready(Some(Ok(msg))),
+                Ok(None) => {}
+                Err(error) => return Poll::Ready(Some(Err(this.fail(error)))),
+            }
+            if this.eof {
+                return Poll::Ready(None);
+            }
+            let buf = this.decoder.buffer_mut();
+            buf.reserve(READ_BUFFER_SIZE);
+            match ready!(poll_read_buf(Pin::new(&mut this.stream), cx, buf)) {
+                Ok(0) => this.eof = true,
+                Ok(_) => {}
+                Err(error) => return Poll::Ready(Some(Err(this.fail(error.into())))),
+            }
+        }
+    }
+}
+
+impl Stream for AsyncTopic {
+    type Item = Result<Msg>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.get_mut().reader).poll_next(cx)
+    }
+}
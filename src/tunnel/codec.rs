@@ -0,0 +1,495 @@
+//! # //! Wire format of the antd tunnel messages
+//!
+//! A frame on the wire is made of:
+//!
+//! ```text
+//! | magic begin (u16) | kind (u8) | channel id (u16) | client id (u16) | size (u32) | payload | magic end (u16) |
+//! ```
+//!
+//! All numbers are big endian. The functions in this module work on
+//! any `Read`/`Write` object or on plain byte buffers so that the
+//! format can be reused outside of `Topic`.
+//!
+//! **Author**: "Dany LE"
+//!
+use super::{Msg, MsgKind};
+use crate::error::{Error, Result};
+use bytes::{Buf, Bytes, BytesMut};
+use std::io::{Read, Write};
+use std::vec::Vec;
+
+/// Magic number at the beginning of a frame
+pub const MSG_MAGIC_BEGIN: u16 = 0x414e;
+/// Magic number at the end of a frame
+pub const MSG_MAGIC_END: u16 = 0x5444;
+/// Size of the frame header: magic, kind, channel id, client id and payload size
+pub const HEADER_SIZE: usize = 11;
+/// Size of the frame trailer: magic end
+pub const TRAILER_SIZE: usize = 2;
+/// Frame trailer bytes
+pub const TRAILER: [u8; TRAILER_SIZE] = MSG_MAGIC_END.to_be_bytes();
+/// Largest payload size that fits in the size field of a frame
+pub const MAX_FRAME_PAYLOAD: usize = u32::MAX as usize;
+/// Default maximum payload size accepted by `read_msg` and `Decoder`
+pub const DEFAULT_MAX_PAYLOAD: u32 = 4 << 20;
+
+/// Decoded frame header
+struct Header {
+    kind: MsgKind,
+    channel_id: u16,
+    client_id: u16,
+    size: u32,
+}
+
+impl Header {
+    /// Size of the whole frame described by the header
+    fn frame_size(&self) -> usize {
+        HEADER_SIZE + self.size as usize + TRAILER_SIZE
+    }
+
+    /// Build the message of the frame from its payload
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - the frame payload
+    fn into_msg(self, data: Bytes) -> Msg {
+        Msg::create(self.kind, self.channel_id, self.client_id, data)
+    }
+}
+
+/// Check the begin magic and the message kind of a header buffer
+/// then decode its fields
+///
+/// # Arguments
+///
+/// * `buf` - buffer of at least `HEADER_SIZE` bytes
+/// * `lenient` - map unsupported kinds to `MsgKind::Unknown` instead of failing
+fn parse_header(buf: &[u8], lenient: bool) -> Result<Header> {
+    let magic = u16::from_be_bytes([buf[0], buf[1]]);
+    if magic != MSG_MAGIC_BEGIN {
+        return Err(Error::Protocol {
+            expected: MSG_MAGIC_BEGIN,
+            got: magic,
+        });
+    }
+    let kind = if lenient {
+        MsgKind::from_u8(buf[2])
+    } else {
+        MsgKind::try_from(buf[2])?
+    };
+    Ok(Header {
+        kind,
+        channel_id: u16::from_be_bytes([buf[3], buf[4]]),
+        client_id: u16::from_be_bytes([buf[5], buf[6]]),
+        size: u32::from_be_bytes([buf[7], buf[8], buf[9], buf[10]]),
+    })
+}
+
+/// Check the end magic of a frame
+///
+/// # Arguments
+///
+/// * `buf` - buffer of at least `TRAILER_SIZE` bytes
+fn check_trailer(buf: &[u8]) -> Result<()> {
+    let magic = u16::from_be_bytes([buf[0], buf[1]]);
+    if magic != MSG_MAGIC_END {
+        return Err(Error::Protocol {
+            expected: MSG_MAGIC_END,
+            got: magic,
+        });
+    }
+    Ok(())
+}
+
+/// Serialize the header of a message
+///
+/// The frame is made of this header, the message payload
+/// and `TRAILER`, which allows to send a shared payload
+/// without copying it. The size field is the length of the
+/// payload, `msg.size` is ignored
+///
+/// # Arguments
+///
+/// * `msg` - the message to encode
+///
+/// # Errors
+///
+/// * `Error::PayloadTooLarge` - the payload does not fit in a frame
+pub fn encode_header(msg: &Msg) -> Result<[u8; HEADER_SIZE]> {
+    let size = u32::try_from(msg.data.len()).map_err(|_| Error::PayloadTooLarge {
+        size: msg.data.len(),
+        max: MAX_FRAME_PAYLOAD,
+    })?;
+    Ok(frame_header(msg, size))
+}
+
+/// Serialize the header of a frame whose payload is sent in parts
+///
+/// # Arguments
+///
+/// * `msg` - the message giving the kind and the ids
+/// * `size` - the payload size
+pub(super) fn frame_header(msg: &Msg, size: u32) -> [u8; HEADER_SIZE] {
+    let mut header = [0; HEADER_SIZE];
+    header[0..2].copy_from_slice(&MSG_MAGIC_BEGIN.to_be_bytes());
+    header[2] = u8::from(msg.kind);
+    header[3..5].copy_from_slice(&msg.channel_id.to_be_bytes());
+    header[5..7].copy_from_slice(&msg.client_id.to_be_bytes());
+    header[7..11].copy_from_slice(&size.to_be_bytes());
+    header
+}
+
+/// Serialize a message and append the frame to a buffer
+///
+/// # Arguments
+///
+/// * `msg` - the message to encode
+/// * `buf` - output buffer
+///
+/// # Errors
+///
+/// * `Error::PayloadTooLarge` - the payload does not fit in a frame
+pub fn encode(msg: &Msg, buf: &mut Vec<u8>) -> Result<()> {
+    let header = encode_header(msg)?;
+    buf.reserve(HEADER_SIZE + msg.data.len() + TRAILER_SIZE);
+    buf.extend_from_slice(&header);
+    buf.extend_from_slice(&msg.data);
+    buf.extend_from_slice(&TRAILER);
+    Ok(())
+}
+
+/// Decode the first frame of a buffer
+///
+/// Return `None` if the buffer does not contain a complete frame yet,
+/// otherwise the message and the number of bytes consumed
+///
+/// # Arguments
+///
+/// * `buf` - input buffer
+///
+/// # Errors
+///
+/// * `Error::Protocol` - the buffer does not start with a valid frame
+/// * `Error::InvalidKind` - unsupported message kind
+pub fn decode(buf: &[u8]) -> Result<Option<(Msg, usize)>> {
+    match check_frame(buf, false, u32::MAX)? {
+        Some(header) => {
+            let size = header.frame_size();
+            let data = Bytes::copy_from_slice(&buf[HEADER_SIZE..size - TRAILER_SIZE]);
+            Ok(Some((header.into_msg(data), size)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Check that a buffer starts with a complete and valid frame
+///
+/// Return the frame header, or `None` if the frame is not complete yet
+///
+/// # Arguments
+///
+/// * `buf` - input buffer
+/// * `lenient` - accept unsupported message kinds
+/// * `max_payload` - maximum accepted payload size
+fn check_frame(buf: &[u8], lenient: bool, max_payload: u32) -> Result<Option<Header>> {
+    if buf.len() < HEADER_SIZE {
+        return Ok(None);
+    }
+    let header = parse_header(&buf[..HEADER_SIZE], lenient)?;
+    if header.size > max_payload {
+        return Err(Error::PayloadTooLarge {
+            size: header.size as usize,
+            max: max_payload as usize,
+        });
+    }
+    let end = HEADER_SIZE + header.size as usize;
+    if buf.len() < end + TRAILER_SIZE {
+        return Ok(None);
+    }
+    check_trailer(&buf[end..end + TRAILER_SIZE])?;
+    Ok(Some(header))
+}
+
+/// Read a complete message from a reader
+///
+/// The payload size is limited to `DEFAULT_MAX_PAYLOAD`
+///
+/// # Arguments
+///
+/// * `reader` - any `Read` object
+///
+/// # Errors
+///
+/// * `Error::Io` - read error
+/// * `Error::Protocol` - invalid frame
+/// * `Error::InvalidKind` - unsupported message kind
+/// * `Error::PayloadTooLarge` - the payload size is above the limit
+pub fn read_msg<R: Read>(reader: &mut R) -> Result<Msg> {
+    read_msg_max(reader, DEFAULT_MAX_PAYLOAD)
+}
+
+/// Read a complete message from a reader with a payload size limit
+///
+/// The payload is allocated only once its size has been checked
+///
+/// # Arguments
+///
+/// * `reader` - any `Read` object
+/// * `max_payload` - maximum accepted payload size
+///
+/// # Errors
+///
+/// See `read_msg`
+pub fn read_msg_max<R: Read>(reader: &mut R, max_payload: u32) -> Result<Msg> {
+    let mut header_buf = [0; HEADER_SIZE];
+    reader.read_exact(&mut header_buf)?;
+    let header = parse_header(&header_buf, false)?;
+    if header.size > max_payload {
+        return Err(Error::PayloadTooLarge {
+            size: header.size as usize,
+            max: max_payload as usize,
+        });
+    }
+    let mut payload = vec![0; header.size as usize];
+    reader.read_exact(&mut payload)?;
+    let mut trailer = [0; TRAILER_SIZE];
+    reader.read_exact(&mut trailer)?;
+    check_trailer(&trailer)?;
+    Ok(header.into_msg(Bytes::from(payload)))
+}
+
+/// Write a message to a writer in one single buffer
+///
+/// # Arguments
+///
+/// * `writer` - any `Write` object
+/// * `msg` - the message
+///
+/// # Errors
+///
+/// * `Error::Io` - write error
+/// * `Error::PayloadTooLarge` - the payload does not fit in a frame
+pub fn write_msg<W: Write>(writer: &mut W, msg: &Msg) -> Result<()> {
+    let mut buf = Vec::new();
+    encode(msg, &mut buf)?;
+    writer.write_all(&buf)?;
+    Ok(())
+}
+
+/// Number of bytes to skip to reach the next candidate begin magic
+///
+/// The first byte is always skipped. If no begin magic is found, the
+/// whole buffer is skipped except a trailing byte that may be the
+/// first half of a magic number
+///
+/// # Arguments
+///
+/// * `buf` - buffer starting with a corrupted frame
+fn next_frame_offset(buf: &[u8]) -> usize {
+    let magic = MSG_MAGIC_BEGIN.to_be_bytes();
+    match buf[1..].windows(2).position(|w| w == magic) {
+        Some(pos) => pos + 1,
+        None if buf.len() > 1 && buf[buf.len() - 1] == magic[0] => buf.len() - 1,
+        None => buf.len(),
+    }
+}
+
+/// Incremental frame decoder
+///
+/// Bytes are accumulated with `feed` or `read_from` as they arrive,
+/// complete frames are then extracted with `decode`.
+///
+/// The payloads of the decoded messages are shared slices of the
+/// decoder buffer, no copy is made. The buffer memory is reused
+/// once all the payloads sharing it have been dropped
+pub struct Decoder {
+    buf: BytesMut,
+    lenient: bool,
+    resync: bool,
+    dropped: u64,
+    max_payload: u32,
+    discard_oversized: bool,
+    discarding: usize,
+    discarded: u64,
+}
+
+impl Decoder {
+    /// Create new empty `Decoder`
+    pub fn new() -> Self {
+        Decoder {
+            buf: BytesMut::new(),
+            lenient: false,
+            resync: false,
+            dropped: 0,
+            max_payload: DEFAULT_MAX_PAYLOAD,
+            discard_oversized: false,
+            discarding: 0,
+            discarded: 0,
+        }
+    }
+
+    /// Set the maximum accepted payload size
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - maximum payload size in bytes
+    pub fn set_max_payload(&mut self, size: u32) {
+        self.max_payload = size;
+    }
+
+    /// Get the maximum accepted payload size
+    pub fn max_payload(&self) -> u32 {
+        self.max_payload
+    }
+
+    /// Skip the frames whose payload is above the limit instead of failing
+    ///
+    /// The oversized frames are never buffered, they are counted in
+    /// `discarded_frames`
+    ///
+    /// # Arguments
+    ///
+    /// * `discard` - true to discard oversized frames
+    pub fn set_discard_oversized(&mut self, discard: bool) {
+        self.discard_oversized = discard;
+    }
+
+    /// Total number of oversized frames discarded
+    pub fn discarded_frames(&self) -> u64 {
+        self.discarded
+    }
+
+    /// Enable or disable the recovery mode
+    ///
+    /// In recovery mode, corrupted or truncated frames are dropped and
+    /// the decoder scans forward for the next begin magic instead of
+    /// failing. The dropped bytes are counted in `dropped_bytes`
+    ///
+    /// # Arguments
+    ///
+    /// * `resync` - true to enable the recovery mode
+    pub fn set_resync(&mut self, resync: bool) {
+        self.resync = resync;
+    }
+
+    /// Total number of bytes dropped by the recovery mode
+    pub fn dropped_bytes(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Enable or disable the lenient mode
+    ///
+    /// In lenient mode, frames with an unsupported message kind are
+    /// decoded as `MsgKind::Unknown` instead of failing
+    ///
+    /// # Arguments
+    ///
+    /// * `lenient` - true to enable the lenient mode
+    pub fn set_lenient(&mut self, lenient: bool) {
+        self.lenient = lenient;
+    }
+
+    /// Append raw bytes to the decoder buffer
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - received bytes
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+        self.skip_discarded();
+    }
+
+    /// Get the decoder buffer, to read data directly into it
+    #[cfg(feature = "async")]
+    pub(crate) fn buffer_mut(&mut self) -> &mut BytesMut {
+        &mut self.buf
+    }
+
+    /// Read bytes from a reader directly into the decoder buffer
+    ///
+    /// Return the number of bytes read, 0 on end of stream
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - any `Read` object
+    /// * `size` - maximum number of bytes to read
+    ///
+    /// # Errors
+    ///
+    /// * `std io error` - read error, including `WouldBlock`
+    pub fn read_from<R: Read>(&mut self, reader: &mut R, size: usize) -> std::io::Result<usize> {
+        let len = self.buf.len();
+        self.buf.resize(len + size, 0);
+        let result = reader.read(&mut self.buf[len..]);
+        self.buf.truncate(len + *result.as_ref().unwrap_or(&0));
+        self.skip_discarded();
+        result
+    }
+
+    /// Drop the buffered bytes of the oversized frame being
+    /// discarded, they are never kept in the buffer
+    fn skip_discarded(&mut self) {
+        let skip = self.discarding.min(self.buf.len());
+        self.buf.advance(skip);
+        self.discarding -= skip;
+    }
+
+    /// Extract the next complete message, if any
+    ///
+    /// # Errors
+    ///
+    /// * `Error::Protocol` - the buffered data is not a valid frame
+    /// * `Error::InvalidKind` - unsupported message kind (strict mode only)
+    /// * `Error::PayloadTooLarge` - the payload size is above the limit
+    ///
+    /// These errors are recovered in recovery mode, oversized frames are
+    /// skipped if discarding is enabled
+    pub fn decode(&mut self) -> Result<Option<Msg>> {
+        loop {
+            self.skip_discarded();
+            if self.discarding > 0 {
+                return Ok(None);
+            }
+            match check_frame(&self.buf, self.lenient, self.max_payload) {
+                Ok(Some(header)) => {
+                    let mut frame = self.buf.split_to(header.frame_size());
+                    frame.advance(HEADER_SIZE);
+                    frame.truncate(header.size as usize);
+                    return Ok(Some(header.into_msg(frame.freeze())));
+                }
+                Ok(None) => return Ok(None),
+                Err(Error::PayloadTooLarge { size, .. }) if self.discard_oversized => {
+                    self.discarding = HEADER_SIZE + size + TRAILER_SIZE;
+                    self.discarded += 1;
+                }
+                Err(Error::Protocol { .. })
+                | Err(Error::InvalidKind(_))
+                | Err(Error::PayloadTooLarge { .. })
+                    if self.resync =>
+                {
+                    let skip = next_frame_offset(&self.buf);
+                    self.buf.advance(skip);
+                    self.dropped += skip as u64;
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /// Number of buffered bytes not yet decoded
+    pub fn pending(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Drop all buffered bytes
+    pub fn clear(&mut self) {
+        self.buf.clear();
+        self.discarding = 0;
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
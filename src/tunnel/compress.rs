@@ -0,0 +1,314 @@
+//! # //! Payload compression
+//!
+//! When enabled on a topic (see `Topic::set_compression`), the
+//! `ChannelData` payloads exchanged with the subscribers that opt in
+//! start with a flag byte giving the compression of the rest of the
+//! payload: `0` for raw data, `1` for deflate and `2` for zstd.
+//! The RPC envelopes are never compressed and have no flag (see `rpc`).
+//! Payloads below the threshold, or that do not shrink, are sent raw.
+//!
+//! A subscriber opts in by listing the algorithms it accepts by order
+//! of preference, either in its subscribe payload, e.g.
+//! `compress:zstd,deflate`, or in a `Compress` control message (see
+//! `ctrl`), e.g. `zstd,deflate`. The topic answers with a `Compress`
+//! control message holding the chosen algorithm, e.g. `zstd`, or
+//! `none` if none of them is supported.
+//!
+//! The algorithms are enabled by the `deflate` and `zstd` features.
+//!
+//! **Author**: "Dany LE"
+//!
+use super::Bytes;
+use crate::error::{Error, Result};
+
+/// Flag of a raw payload
+pub const FLAG_RAW: u8 = 0;
+/// Prefix of the negotiation in a subscribe payload
+pub const SUBSCRIBE_PREFIX: &str = "compress:";
+
+/// Compression algorithm of a payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Compression {
+    /// Raw deflate stream (RFC 1951)
+    Deflate = 1,
+    /// Zstandard frame
+    Zstd = 2,
+}
+
+/// Compression policy of a `Topic`
+#[derive(Debug, Clone)]
+pub struct CompressionPolicy {
+    /// Payloads below this size are never compressed
+    pub threshold: usize,
+    /// Maximum size of a decompressed payload
+    pub max_size: usize,
+}
+
+impl Default for CompressionPolicy {
+    fn default() -> Self {
+        CompressionPolicy {
+            threshold: 1024,
+            max_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+impl Compression {
+    /// Algorithms enabled in this build
+    pub fn supported() -> Vec<Compression> {
+        let mut supported = Vec::new();
+        if cfg!(feature = "zstd") {
+            supported.push(Compression::Zstd);
+        }
+        if cfg!(feature = "deflate") {
+            supported.push(Compression::Deflate);
+        }
+        supported
+    }
+
+    /// Name of the algorithm in the negotiation messages
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::Deflate => "deflate",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    /// Get an algorithm from its name
+    ///
+    /// Arguments
+    ///
+    /// * `name` - the name
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "deflate" => Some(Compression::Deflate),
+            "zstd" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Get an algorithm from the flag of a payload
+    ///
+    /// Arguments
+    ///
+    /// * `flag` - the flag
+    pub fn from_flag(flag: u8) -> Option<Self> {
+        match flag {
+            1 => Some(Compression::Deflate),
+            2 => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Compress data
+    ///
+    /// Arguments
+    ///
+    /// * `data` - the data
+    ///
+    /// # Errors
+    ///
+    /// * `Error::Compression` - the algorithm is not enabled in this build
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::Deflate => deflate(data),
+            Compression::Zstd => zstd_compress(data),
+        }
+    }
+
+    /// Decompress data
+    ///
+    /// Arguments
+    ///
+    /// * `data` - the compressed data
+    /// * `max_size` - the maximum size of the decompressed data
+    ///
+    /// # Errors
+    ///
+    /// * `Error::Compression` - the data is invalid, above `max_size`,
+    ///   or the algorithm is not enabled in this build
+    pub fn decompress(&self, data: &[u8], max_size: usize) -> Result<Vec<u8>> {
+        let data = match self {
+            Compression::Deflate => inflate(data, max_size)?,
+            Compression::Zstd => zstd_decompress(data, max_size)?,
+        };
+        if data.len() > max_size {
+            return Err(Error::Compression(format!(
+                "Decompressed payload above the limit of {} bytes",
+                max_size
+            )));
+        }
+        Ok(data)
+    }
+}
+
+/// Get the algorithms requested in a subscribe payload
+///
+/// Return `None` if the payload does not request compression
+///
+/// Arguments
+///
+/// * `data` - the subscribe payload
+pub fn subscribe_request(data: &[u8]) -> Option<&[u8]> {
+    data.strip_prefix(SUBSCRIBE_PREFIX.as_bytes())
+}
+
+/// Choose the first supported algorithm of a request
+///
+/// Arguments
+///
+/// * `names` - the comma separated names of the algorithms
+pub fn negotiate(names: &[u8]) -> Option<Compression> {
+    let supported = Compression::supported();
+    String::from_utf8_lossy(names)
+        .split(',')
+        .filter_map(|name| Compression::from_name(name.trim()))
+        .find(|compression| supported.contains(compression))
+}
+
+/// Build the answer to a negotiation request
+///
+/// Arguments
+///
+/// * `chosen` - the chosen algorithm
+pub fn negotiation_reply(chosen: Option<Compression>) -> &'static str {
+    chosen
+        .map(|compression| compression.name())
+        .unwrap_or("none")
+}
+
+/// Encode a payload with its flag
+///
+/// Return the parts of the payload, raw data is not copied
+///
+/// Arguments
+///
+/// * `compression` - the algorithm
+/// * `threshold` - the size below which the data is sent raw
+/// * `data` - the data
+///
+/// # Errors
+///
+/// * any error returned by `Compression::compress`
+pub fn encode(compression: Compression, threshold: usize, data: &Bytes) -> Result<Vec<Bytes>> {
+    if data.len() >= threshold {
+        let compressed = compression.compress(data)?;
+        if compressed.len() < data.len() {
+            let mut payload = Vec::with_capacity(compressed.len() + 1);
+            payload.push(compression as u8);
+            payload.extend_from_slice(&compressed);
+            return Ok(vec![Bytes::from(payload)]);
+        }
+    }
+    Ok(vec![Bytes::from_static(&[FLAG_RAW]), data.clone()])
+}
+
+/// Decode a payload starting with its flag
+///
+/// Arguments
+///
+/// * `data` - the payload
+/// * `max_size` - the maximum size of the decompressed data
+///
+/// # Errors
+///
+/// * `Error::Compression` - the flag or the compressed data is invalid
+pub fn decode(data: &Bytes, max_size: usize) -> Result<Bytes> {
+    let flag = match data.first() {
+        Some(flag) => *flag,
+        None => return Err(Error::Compression(String::from("Missing payload flag"))),
+    };
+    if flag == FLAG_RAW {
+        return Ok(data.slice(1..));
+    }
+    match Compression::from_flag(flag) {
+        Some(compression) => Ok(Bytes::from(compression.decompress(&data[1..], max_size)?)),
+        None => Err(Error::Compression(format!(
+            "Invalid payload flag {:#02x}",
+            flag
+        ))),
+    }
+}
+
+/// Error of an algorithm not enabled in this build
+///
+/// Arguments
+///
+/// * `compression` - the algorithm
+#[cfg(not(all(feature = "deflate", feature = "zstd")))]
+fn unsupported(compression: Compression) -> Error {
+    Error::Compression(format!(
+        "{} is not enabled in this build",
+        compression.name()
+    ))
+}
+
+/// Convert an error of a compression library to `Error::Compression`
+///
+/// Arguments
+///
+/// * `error` - the error of the library
+#[cfg(any(feature = "deflate", feature = "zstd"))]
+fn compression_error(error: impl std::fmt::Display) -> Error {
+    Error::Compression(error.to_string())
+}
+
+#[cfg(feature = "deflate")]
+fn deflate(data: &[u8]) -> Result<Vec<u8>> {
+    use std::io::Write;
+    let mut encoder =
+        flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data).map_err(compression_error)?;
+    encoder.finish().map_err(compression_error)
+}
+
+#[cfg(not(feature = "deflate"))]
+fn deflate(_: &[u8]) -> Result<Vec<u8>> {
+    Err(unsupported(Compression::Deflate))
+}
+
+#[cfg(feature = "deflate")]
+fn inflate(data: &[u8], max_size: usize) -> Result<Vec<u8>> {
+    use std::io::Read;
+    let mut output = Vec::new();
+    // one more byte to detect the data above the limit
+    flate2::read::DeflateDecoder::new(data)
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut output)
+        .map_err(compression_error)?;
+    Ok(output)
+}
+
+#[cfg(not(feature = "deflate"))]
+fn inflate(_: &[u8], _: usize) -> Result<Vec<u8>> {
+    Err(unsupported(Compression::Deflate))
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_compress(data: &[u8]) -> Result<Vec<u8>> {
+    zstd::stream::encode_all(data, 0).map_err(compression_error)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_compress(_: &[u8]) -> Result<Vec<u8>> {
+    Err(unsupported(Compression::Zstd))
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_decompress(data: &[u8], max_size: usize) -> Result<Vec<u8>> {
+    use std::io::Read;
+    let mut output = Vec::new();
+    // one more byte to detect the data above the limit
+    zstd::stream::read::Decoder::new(data)
+        .map_err(compression_error)?
+        .take(max_size as u64 + 1)
+        .read_to_end(&mut output)
+        .map_err(compression_error)?;
+    Ok(output)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_decompress(_: &[u8], _: usize) -> Result<Vec<u8>> {
+    Err(unsupported(Compression::Zstd))
+}
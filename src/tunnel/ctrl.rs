@@ -0,0 +1,164 @@
+//! # //! Control sub-protocol
+//!
+//! The payload of a `ChannelCtrl` message is an opcode byte followed
+//! by the payload of the command. The topic answers the built-in
+//! commands itself:
+//!
+//! * `Ping` (`0x01`) - answered with a `Pong` (`0x02`) carrying the
+//!   same payload
+//! * `Stats` (`0x03`) - answered with the statistics of the client
+//! * `Info` (`0x04`) - answered with the information of the topic
+//! * `Compress` (`0x05`) - compression negotiation (see `compress`)
+//!
+//! The opcodes from `0x80` are reserved to the application. Handlers
+//! of any opcode are registered with `Topic::on_ctrl`, the other
+//! control messages are passed to the topic handler.
+//!
+//! The `Pong` answers of the tunnel service (client id `0`) to the
+//! heartbeat of the topic (see `Topic::set_heartbeat`) are consumed
+//! by the topic.
+//!
+//! The statistics and the information are sent as `key=value` lines.
+//!
+//! **Author**: "Dany LE"
+//!
+use super::{Bytes, Topic};
+use crate::error::Result;
+use bytes::{BufMut, BytesMut};
+
+/// First opcode reserved to the application
+pub const CTRL_APP_BASE: u8 = 0x80;
+
+/// Handler of a control opcode
+///
+/// The handler receives the topic, the client id and the command
+/// payload, and returns the payload of the answer if any
+pub type CtrlHandle = dyn FnMut(&mut Topic, u16, Bytes) -> Result<Option<Bytes>> + Send;
+
+/// Opcode of a control message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CtrlOp {
+    /// Liveness request
+    Ping,
+    /// Answer to a `Ping`
+    Pong,
+    /// Statistics of the client
+    Stats,
+    /// Information of the topic
+    Info,
+    /// Compression negotiation
+    Compress,
+    /// Application defined command, from `CTRL_APP_BASE`
+    App(u8),
+    /// Unassigned opcode
+    Unknown(u8),
+}
+
+/// Control message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CtrlMsg {
+    pub op: CtrlOp,
+    pub payload: Bytes,
+}
+
+impl CtrlOp {
+    /// Opcode of the answer to a command
+    pub fn reply(&self) -> CtrlOp {
+        match self {
+            CtrlOp::Ping => CtrlOp::Pong,
+            op => *op,
+        }
+    }
+}
+
+impl From<u8> for CtrlOp {
+    fn from(value: u8) -> Self {
+        match value {
+            0x01 => CtrlOp::Ping,
+            0x02 => CtrlOp::Pong,
+            0x03 => CtrlOp::Stats,
+            0x04 => CtrlOp::Info,
+            0x05 => CtrlOp::Compress,
+            op if op >= CTRL_APP_BASE => CtrlOp::App(op),
+            op => CtrlOp::Unknown(op),
+        }
+    }
+}
+
+impl From<CtrlOp> for u8 {
+    fn from(op: CtrlOp) -> Self {
+        match op {
+            CtrlOp::Ping => 0x01,
+            CtrlOp::Pong => 0x02,
+            CtrlOp::Stats => 0x03,
+            CtrlOp::Info => 0x04,
+            CtrlOp::Compress => 0x05,
+            CtrlOp::App(op) | CtrlOp::Unknown(op) => op,
+        }
+    }
+}
+
+impl CtrlMsg {
+    /// Create new `CtrlMsg` object
+    ///
+    /// Arguments
+    ///
+    /// * `op` - the opcode
+    /// * `payload` - the command payload
+    pub fn create(op: CtrlOp, payload: impl Into<Bytes>) -> Self {
+        CtrlMsg {
+            op,
+            payload: payload.into(),
+        }
+    }
+
+    /// Serialize the message into a `ChannelCtrl` payload
+    pub fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(1 + self.payload.len());
+        buf.put_u8(u8::from(self.op));
+        buf.put_slice(&self.payload);
+        buf.freeze()
+    }
+
+    /// Parse the payload of a `ChannelCtrl` message, the command
+    /// payload shares the buffer of `data`
+    ///
+    /// Return `None` if the payload is empty
+    ///
+    /// Arguments
+    ///
+    /// * `data` - the message payload
+    pub fn decode(data: &Bytes) -> Option<Self> {
+        let op = CtrlOp::from(*data.first()?);
+        Some(CtrlMsg {
+            op,
+            payload: data.slice(1..),
+        })
+    }
+}
+
+/// Serialize `key=value` lines
+///
+/// Arguments
+///
+/// * `fields` - the keys and the values
+pub fn encode_fields(fields: &[(&str, String)]) -> Bytes {
+    let lines: Vec<String> = fields
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect();
+    Bytes::from(lines.join("\n"))
+}
+
+/// Parse `key=value` lines, invalid lines are ignored
+///
+/// Arguments
+///
+/// * `data` - the payload
+pub fn decode_fields(data: &[u8]) -> Vec<(String, String)> {
+    String::from_utf8_lossy(data)
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (String::from(key), String::from(value)))
+        .collect()
+}
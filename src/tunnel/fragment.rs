@@ -0,0 +1,300 @@
+//! # //! Fragmentation of large messages
+//!
+//! When enabled on a topic (see `Topic::set_fragmentation`), the
+//! `ChannelData` payloads above a threshold are split into numbered
+//! fragments and the fragments received from the clients are
+//! reassembled before being passed to the topic handler.
+//!
+//! While fragmentation is enabled, the payload of every `ChannelData`
+//! message starts with a flag byte. A complete message is sent as:
+//!
+//! ```text
+//! 0 (u8) | data
+//! ```
+//!
+//! and a fragment as:
+//!
+//! ```text
+//! 1 (u8) | message id (u16) | index (u16) | count (u16) | data
+//! ```
+//!
+//! The numbers are big endian. Payloads without a valid flag or
+//! fragment header are dropped, except the RPC envelopes which are
+//! never fragmented (see `rpc`).
+//!
+//! **Author**: "Dany LE"
+//!
+use super::Bytes;
+use crate::error::{Error, Result};
+use bytes::BytesMut;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Flag of a complete message
+pub const FLAG_WHOLE: u8 = 0;
+/// Flag of a fragment
+pub const FLAG_FRAGMENT: u8 = 1;
+/// Size of a fragment header, including its flag
+pub const FRAGMENT_HEADER_SIZE: usize = 7;
+
+/// Fragmentation policy of a `Topic`
+#[derive(Debug, Clone)]
+pub struct FragmentPolicy {
+    /// Payloads above this size are split into fragments carrying
+    /// at most this number of bytes
+    pub threshold: usize,
+    /// Maximum size of a reassembled message
+    pub max_size: usize,
+    /// Maximum time between the first and the last fragment of a message
+    pub timeout: Duration,
+}
+
+/// Header of a fragment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FragmentHeader {
+    /// Identifier of the fragmented message
+    pub id: u16,
+    /// Position of the fragment in the message
+    pub index: u16,
+    /// Number of fragments of the message
+    pub count: u16,
+}
+
+/// Result of feeding a payload to the reassembly buffers
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum Reassembled {
+    /// The payload is passed as is, fragmentation is disabled
+    Plain,
+    /// Payload of a reassembled message
+    Complete(Bytes),
+    /// Fragment waiting for the rest of its message
+    Partial,
+    /// The fragment and its message are dropped
+    Dropped(&'static str),
+}
+
+/// Message being reassembled
+#[derive(Debug)]
+struct Reassembly {
+    id: u16,
+    count: u16,
+    next: u16,
+    data: BytesMut,
+    started: Instant,
+}
+
+/// Fragmentation state of a topic
+#[derive(Debug)]
+pub(super) struct Fragmenter {
+    policy: FragmentPolicy,
+    n_id: u16,
+    buffers: HashMap<u16, Reassembly>,
+}
+
+impl Default for FragmentPolicy {
+    fn default() -> Self {
+        FragmentPolicy {
+            threshold: 64 * 1024,
+            max_size: 16 * 1024 * 1024,
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl FragmentHeader {
+    /// Serialize the header with its flag
+    pub fn encode(&self) -> [u8; FRAGMENT_HEADER_SIZE] {
+        let mut header = [0; FRAGMENT_HEADER_SIZE];
+        header[0] = FLAG_FRAGMENT;
+        header[1..3].copy_from_slice(&self.id.to_be_bytes());
+        header[3..5].copy_from_slice(&self.index.to_be_bytes());
+        header[5..7].copy_from_slice(&self.count.to_be_bytes());
+        header
+    }
+
+    /// Parse the header of a fragment
+    ///
+    /// Return `None` if the payload is not a valid fragment
+    ///
+    /// Arguments
+    ///
+    /// * `data` - the message payload
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() < FRAGMENT_HEADER_SIZE || data[0] != FLAG_FRAGMENT {
+            return None;
+        }
+        let number = |i: usize| u16::from_be_bytes([data[i], data[i + 1]]);
+        let header = FragmentHeader {
+            id: number(1),
+            index: number(3),
+            count: number(5),
+        };
+        if header.count < 2 || header.index >= header.count {
+            return None;
+        }
+        Some(header)
+    }
+}
+
+impl Fragmenter {
+    /// Create new `Fragmenter` object
+    ///
+    /// Arguments
+    ///
+    /// * `policy` - the fragmentation policy
+    pub fn new(policy: FragmentPolicy) -> Self {
+        Fragmenter {
+            policy,
+            n_id: 0,
+            buffers: HashMap::new(),
+        }
+    }
+
+    /// Get the fragmentation policy
+    pub fn policy(&self) -> &FragmentPolicy {
+        &self.policy
+    }
+
+    /// Add the flag to a payload and split it into fragments if
+    /// it is above the threshold
+    ///
+    /// Return the parts of each message to send. Each fragment is
+    /// made of its header and a slice of the payload, a payload
+    /// sent as a single message is not copied.
+    ///
+    /// Arguments
+    ///
+    /// * `parts` - the parts of the payload
+    ///
+    /// # Errors
+    ///
+    /// * `Error::PayloadTooLarge` - the payload needs more than
+    ///   `u16::MAX` fragments
+    pub fn split(&mut self, mut parts: Vec<Bytes>) -> Result<Vec<Vec<Bytes>>> {
+        let size = self.policy.threshold.max(1);
+        let len: usize = parts.iter().map(|part| part.len()).sum();
+        if len <= size {
+            parts.insert(0, Bytes::from_static(&[FLAG_WHOLE]));
+            return Ok(vec![parts]);
+        }
+        let data = match parts.len() {
+            1 => parts.remove(0),
+            _ => Bytes::from(parts.concat()),
+        };
+        let count =
+            u16::try_from(data.len().div_ceil(size)).map_err(|_| Error::PayloadTooLarge {
+                size: data.len(),
+                max: size.saturating_mul(u16::MAX as usize),
+            })?;
+        let id = self.n_id;
+        self.n_id = self.n_id.wrapping_add(1);
+        let fragments = (0..count)
+            .map(|index| {
+                let header = FragmentHeader { id, index, count };
+                let start = index as usize * size;
+                let end = data.len().min(start + size);
+                vec![
+                    Bytes::copy_from_slice(&header.encode()),
+                    data.slice(start..end),
+                ]
+            })
+            .collect();
+        Ok(fragments)
+    }
+
+    /// Add a payload received from a client to its reassembly buffer
+    ///
+    /// The fragments of a client are expected in order, the first
+    /// fragment of a message replaces any incomplete message of the
+    /// client
+    ///
+    /// Arguments
+    ///
+    /// * `client_id` - the client id
+    /// * `data` - the payload
+    /// * `now` - the current time
+    pub fn feed(&mut self, client_id: u16, data: &Bytes, now: Instant) -> Reassembled {
+        match data.first() {
+            Some(&FLAG_WHOLE) => return Reassembled::Complete(data.slice(1..)),
+            Some(&FLAG_FRAGMENT) => {}
+            _ => return Reassembled::Dropped("missing fragmentation flag"),
+        }
+        let header = match FragmentHeader::decode(data) {
+            Some(header) => header,
+            None => return Reassembled::Dropped("invalid fragment header"),
+        };
+        if header.index == 0 {
+            let buffer = Reassembly {
+                id: header.id,
+                count: header.count,
+                next: 0,
+                data: BytesMut::new(),
+                started: now,
+            };
+            let _ = self.buffers.insert(client_id, buffer);
+        }
+        let buffer = match self.buffers.get_mut(&client_id) {
+            Some(buffer) => buffer,
+            None => return Reassembled::Dropped("fragment out of sequence"),
+        };
+        if buffer.id != header.id || buffer.count != header.count || buffer.next != header.index {
+            let _ = self.buffers.remove(&client_id);
+            return Reassembled::Dropped("fragment out of sequence");
+        }
+        let chunk = &data[FRAGMENT_HEADER_SIZE..];
+        if buffer.data.len() + chunk.len() > self.policy.max_size {
+            let _ = self.buffers.remove(&client_id);
+            return Reassembled::Dropped("message above the maximum size");
+        }
+        buffer.data.extend_from_slice(chunk);
+        buffer.next += 1;
+        if buffer.next < buffer.count {
+            return Reassembled::Partial;
+        }
+        match self.buffers.remove(&client_id) {
+            Some(buffer) => Reassembled::Complete(buffer.data.freeze()),
+            None => Reassembled::Partial,
+        }
+    }
+
+    /// Deadline of the oldest reassembly
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let started = self.buffers.values().map(|buffer| buffer.started).min()?;
+        Some(started + self.policy.timeout)
+    }
+
+    /// Drop the messages whose reassembly has timed out
+    ///
+    /// Return the ids of the clients whose message is dropped
+    ///
+    /// Arguments
+    ///
+    /// * `now` - the current time
+    pub fn expire(&mut self, now: Instant) -> Vec<u16> {
+        let timeout = self.policy.timeout;
+        let expired: Vec<u16> = self
+            .buffers
+            .iter()
+            .filter(|(_, buffer)| now.duration_since(buffer.started) >= timeout)
+            .map(|(client_id, _)| *client_id)
+            .collect();
+        for client_id in expired.iter() {
+            let _ = self.buffers.remove(client_id);
+        }
+        expired
+    }
+
+    /// Drop the reassembly buffer of a client
+    ///
+    /// Arguments
+    ///
+    /// * `client_id` - the client id
+    pub fn remove(&mut self, client_id: u16) {
+        let _ = self.buffers.remove(&client_id);
+    }
+
+    /// Drop all the reassembly buffers
+    pub fn clear(&mut self) {
+        self.buffers.clear();
+    }
+}
@@ -0,0 +1,216 @@
+//! # //! Event handler of a topic
+//!
+//! `TopicHandler` splits the events of a `Topic` into one method
+//! per message kind and per event type. All methods do nothing by
+//! default, a handler only implements the ones it is interested in.
+//!
+//! Any `FnMut(&CallbackEvent, &mut Topic)` closure is also a handler
+//! receiving the raw events (see `Topic::on_message`).
+//!
+//! **Author**: "Dany LE"
+//!
+use super::{CallbackEvent, IOEvent, Msg, MsgKind, Signal, TimerId, Topic};
+use crate::error::Result;
+use std::os::unix::io::RawFd;
+
+/// Event handler of a `Topic`
+///
+pub trait TopicHandler: Send {
+    /// Handle a raw topic event
+    ///
+    /// The default implementation dispatches the event to the
+    /// other methods of the handler
+    ///
+    /// Arguments
+    ///
+    /// * `evt` - the event
+    /// * `topic` - the topic receiving the event
+    fn on_event(&mut self, evt: &CallbackEvent, topic: &mut Topic) -> Result<()> {
+        dispatch(self, evt, topic)
+    }
+
+    /// A client subscribes to the channel
+    ///
+    /// Arguments
+    ///
+    /// * `topic` - the topic
+    /// * `client_id` - the client id
+    fn on_subscribe(&mut self, _topic: &mut Topic, _client_id: u16) -> Result<()> {
+        Ok(())
+    }
+
+    /// A new subscriber is added to the topic, its application data
+    /// is available with `Topic::subscriber_data`
+    ///
+    /// Arguments
+    ///
+    /// * `topic` - the topic
+    /// * `client_id` - the client id
+    fn on_join(&mut self, _topic: &mut Topic, _client_id: u16) -> Result<()> {
+        Ok(())
+    }
+
+    /// A subscriber leaves the topic for any reason (unsubscription,
+    /// lost connection, topic closing), it is still available in
+    /// `Topic::subscribers` until the method returns
+    ///
+    /// Arguments
+    ///
+    /// * `topic` - the topic
+    /// * `client_id` - the client id
+    fn on_leave(&mut self, _topic: &mut Topic, _client_id: u16) -> Result<()> {
+        Ok(())
+    }
+
+    /// A client unsubscribes from the channel
+    ///
+    /// Arguments
+    ///
+    /// * `topic` - the topic
+    /// * `client_id` - the client id
+    fn on_unsubscribe(&mut self, _topic: &mut Topic, _client_id: u16) -> Result<()> {
+        Ok(())
+    }
+
+    /// All the clients must be unsubscribed from the channel,
+    /// e.g. the topic is being closed
+    ///
+    /// Arguments
+    ///
+    /// * `topic` - the topic
+    fn on_unsubscribe_all(&mut self, _topic: &mut Topic) -> Result<()> {
+        Ok(())
+    }
+
+    /// A client sends data to the channel
+    ///
+    /// Arguments
+    ///
+    /// * `topic` - the topic
+    /// * `client_id` - the client id
+    /// * `data` - the payload
+    fn on_data(&mut self, _topic: &mut Topic, _client_id: u16, _data: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    /// A client sends a control message to the channel
+    ///
+    /// Arguments
+    ///
+    /// * `topic` - the topic
+    /// * `client_id` - the client id
+    /// * `data` - the payload
+    fn on_ctrl(&mut self, _topic: &mut Topic, _client_id: u16, _data: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Any other message received from the tunnel
+    ///
+    /// Arguments
+    ///
+    /// * `topic` - the topic
+    /// * `msg` - the message
+    fn on_message(&mut self, _topic: &mut Topic, _msg: &Msg) -> Result<()> {
+        Ok(())
+    }
+
+    /// A file descriptor registered with `Topic::register_io` is ready
+    ///
+    /// Arguments
+    ///
+    /// * `topic` - the topic
+    /// * `fd` - the file descriptor
+    /// * `event` - the readiness event
+    fn on_io(&mut self, _topic: &mut Topic, _fd: RawFd, _event: &IOEvent) -> Result<()> {
+        Ok(())
+    }
+
+    /// Nothing happens before the step timeout
+    ///
+    /// Arguments
+    ///
+    /// * `topic` - the topic
+    fn on_idle(&mut self, _topic: &mut Topic) -> Result<()> {
+        Ok(())
+    }
+
+    /// A timer of the topic has expired
+    ///
+    /// Arguments
+    ///
+    /// * `topic` - the topic
+    /// * `id` - the timer identifier
+    fn on_timer(&mut self, _topic: &mut Topic, _id: TimerId) -> Result<()> {
+        Ok(())
+    }
+
+    /// A signal generated by the topic itself
+    ///
+    /// Arguments
+    ///
+    /// * `topic` - the topic
+    /// * `signal` - the signal
+    fn on_signal(&mut self, _topic: &mut Topic, _signal: Signal) -> Result<()> {
+        Ok(())
+    }
+
+    /// The topic is being closed, the channel is still open
+    ///
+    /// Arguments
+    ///
+    /// * `topic` - the topic
+    fn on_close(&mut self, _topic: &mut Topic) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Dispatch a raw topic event to the methods of a handler
+///
+/// This is the default implementation of `TopicHandler::on_event`,
+/// a handler overriding `on_event` can call it for the events it
+/// does not process itself
+///
+/// Arguments
+///
+/// * `handler` - the handler
+/// * `evt` - the event
+/// * `topic` - the topic receiving the event
+pub fn dispatch<H>(handler: &mut H, evt: &CallbackEvent, topic: &mut Topic) -> Result<()>
+where
+    H: TopicHandler + ?Sized,
+{
+    if let Some(signal) = evt.signal {
+        return match signal {
+            Signal::Closing => handler.on_close(topic),
+            Signal::Joined(client_id) => handler.on_join(topic, client_id),
+            Signal::Left(client_id) => handler.on_leave(topic, client_id),
+            Signal::Timer(id) => handler.on_timer(topic, id),
+            _ => handler.on_signal(topic, signal),
+        };
+    }
+    if let Some(msg) = evt.msg {
+        return match msg.kind {
+            MsgKind::ChannelSubscribe => handler.on_subscribe(topic, msg.client_id),
+            MsgKind::ChannelUnsubscribe => handler.on_unsubscribe(topic, msg.client_id),
+            MsgKind::ChannelUnsubscribeAll => handler.on_unsubscribe_all(topic),
+            MsgKind::ChannelData => handler.on_data(topic, msg.client_id, &msg.data),
+            MsgKind::ChannelCtrl => handler.on_ctrl(topic, msg.client_id, &msg.data),
+            _ => handler.on_message(topic, msg),
+        };
+    }
+    match (evt.fd, evt.event) {
+        (Some(fd), Some(event)) => handler.on_io(topic, fd, event),
+        (None, None) => handler.on_idle(topic),
+        // event of a file descriptor no longer registered
+        _ => Ok(()),
+    }
+}
+
+impl<F> TopicHandler for F
+where
+    F: FnMut(&CallbackEvent, &mut Topic) -> Result<()> + Send,
+{
+    fn on_event(&mut self, evt: &CallbackEvent, topic: &mut Topic) -> Result<()> {
+        self(evt, topic)
+    }
+}
@@ -0,0 +1,247 @@
+//! # //! Event loop hosting several topics
+//!
+//! A `Reactor` owns a single poll object shared by all its topics,
+//! each topic keeps its own tunnel connection, callback and
+//! registered file descriptors. Events are dispatched to the topics
+//! by token, so one thread can serve many channels. A topic
+//! failing with an error is closed and removed, the other topics
+//! are not affected.
+//!
+//! **Author**: "Dany LE"
+//!
+use super::{ShutdownHandle, Topic, MAX_EVT_CAPACITY, TOPIC_TOKEN_BITS, WAKER};
+use crate::error::{Error, Result};
+use crate::utils::{LogLevel, LOG};
+use crate::ERROR;
+use mio::{Events, Poll, Waker};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Identifier of a topic hosted by a `Reactor`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TopicId(usize);
+
+/// Event loop hosting several topics
+pub struct Reactor {
+    poll: Poll,
+    topics: Vec<Option<Topic>>,
+    stepto: Option<Duration>,
+    waker: Arc<Waker>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl Reactor {
+    /// Create new `Reactor` object
+    ///
+    /// # Errors
+    ///
+    /// * `Error::Io` - unable to create the poll object or its waker
+    pub fn new() -> Result<Self> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        Ok(Reactor {
+            poll,
+            topics: Vec::new(),
+            stepto: None,
+            waker,
+            shutdown: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Add a topic to the reactor
+    ///
+    /// The topic can be added before or after it is opened, its
+    /// sockets are moved to the poll object of the reactor. From
+    /// now on, the topic is stepped by `Reactor::step`.
+    ///
+    /// Arguments
+    ///
+    /// * `topic` - the topic
+    ///
+    /// # Errors
+    ///
+    /// * `Error::Other` - the topic is already hosted by a reactor
+    ///   or the reactor is full
+    /// * `Error::Io` - unable to register the topic sockets
+    pub fn add(&mut self, mut topic: Topic) -> Result<TopicId> {
+        if topic.registry.is_some() && topic.poll.is_none() {
+            return Err(Error::Other(format!(
+                "Topic {} is already hosted by a reactor",
+                topic.name
+            )));
+        }
+        let index = self.topics.len();
+        if index >= usize::MAX >> TOPIC_TOKEN_BITS {
+            return Err(Error::Other(String::from("Too many topics in reactor")));
+        }
+        let registry = self.poll.registry().try_clone()?;
+        topic.attach(registry, index << TOPIC_TOKEN_BITS, self.waker.clone())?;
+        self.topics.push(Some(topic));
+        Ok(TopicId(index))
+    }
+
+    /// Get a topic hosted by the reactor
+    ///
+    /// Arguments
+    ///
+    /// * `id` - the topic identifier
+    pub fn topic(&self, id: TopicId) -> Option<&Topic> {
+        self.topics.get(id.0).and_then(Option::as_ref)
+    }
+
+    /// Get a mutable reference to a topic hosted by the reactor
+    ///
+    /// Arguments
+    ///
+    /// * `id` - the topic identifier
+    pub fn topic_mut(&mut self, id: TopicId) -> Option<&mut Topic> {
+        self.topics.get_mut(id.0).and_then(Option::as_mut)
+    }
+
+    /// Remove a topic from the reactor, the topic is closed
+    ///
+    /// Return false if the topic is not hosted by the reactor
+    ///
+    /// Arguments
+    ///
+    /// * `id` - the topic identifier
+    pub fn remove(&mut self, id: TopicId) -> bool {
+        // the slot is kept so that the tokens are never reused
+        self.topics.get_mut(id.0).and_then(Option::take).is_some()
+    }
+
+    /// Number of topics hosted by the reactor
+    pub fn len(&self) -> usize {
+        self.topics.iter().filter(|topic| topic.is_some()).count()
+    }
+
+    /// Check if the reactor hosts no topic
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Set the maximum time waited for events in `step`
+    ///
+    /// Arguments
+    ///
+    /// * `to` - the timeout
+    pub fn set_step_to(&mut self, to: Duration) {
+        self.stepto = Some(to);
+    }
+
+    /// Wait for events on the sockets of all topics and dispatch
+    /// them to the owning topics
+    ///
+    /// The poll timeout is the shortest of the reactor and topic
+    /// timeouts (including the topic timers). Every topic receives
+    /// an empty event if nothing happens on it during the step
+    /// timeout of the reactor or of the topic.
+    ///
+    /// The topics whose shutdown has been requested (see
+    /// `Topic::shutdown_handle`) are closed and removed.
+    ///
+    /// A topic whose callback or tunnel connection returns an error
+    /// is closed and removed, the other topics are stepped anyway.
+    /// Return the removed topics with their error.
+    ///
+    /// # Errors
+    ///
+    /// * `Error::Io` - unable to poll the sockets
+    pub fn step(&mut self) -> Result<Vec<(TopicId, Error)>> {
+        let mut events = Events::with_capacity(MAX_EVT_CAPACITY);
+        let timeout = self
+            .topics
+            .iter()
+            .flatten()
+            .filter_map(|topic| topic.timeout(self.stepto))
+            .chain(self.stepto)
+            .min();
+        self.poll.poll(&mut events, timeout)?;
+        let mut failed = Vec::new();
+        for event in events.iter().filter(|event| event.token() != WAKER) {
+            let index = event.token().0 >> TOPIC_TOKEN_BITS;
+            // events of removed topics are ignored
+            if let Some(Some(topic)) = self.topics.get_mut(index) {
+                if let Err(error) = topic.handle_event(event) {
+                    failed.push(self.fail(index, error));
+                }
+            }
+        }
+        for index in 0..self.topics.len() {
+            if let Some(topic) = self.topics[index].as_mut() {
+                if let Err(error) = topic.tick(self.stepto) {
+                    failed.push(self.fail(index, error));
+                }
+            }
+        }
+        for (index, slot) in self.topics.iter_mut().enumerate() {
+            if let Some(mut topic) = slot.take_if(|topic| topic.is_shutdown()) {
+                if let Err(error) = topic.close() {
+                    ERROR!("Unable to close topic [{}]: {}", topic.name, error);
+                    failed.push((TopicId(index), error));
+                }
+            }
+        }
+        Ok(failed)
+    }
+
+    /// Close and remove a topic that has returned an error
+    ///
+    /// Arguments
+    ///
+    /// * `index` - the slot of the topic
+    /// * `error` - the error of the topic
+    fn fail(&mut self, index: usize, error: Error) -> (TopicId, Error) {
+        if let Some(mut topic) = self.topics[index].take() {
+            ERROR!("Topic [{}] removed from reactor: {}", topic.name, error);
+            if let Err(error) = topic.close() {
+                ERROR!("Unable to close topic [{}]: {}", topic.name, error);
+            }
+        }
+        (TopicId(index), error)
+    }
+
+    /// Step the reactor until the shutdown is requested with a
+    /// `ShutdownHandle`, then close all the topics
+    ///
+    /// The topics removed after an error are logged by `step`
+    ///
+    /// # Errors
+    ///
+    /// * `Error::Io` - unable to poll the sockets, the topics are
+    ///   closed before the error is returned
+    /// * the first error returned by `Topic::close`
+    pub fn run(&mut self) -> Result<()> {
+        while !self.shutdown.load(Ordering::SeqCst) {
+            if let Err(error) = self.step() {
+                if let Err(error) = self.close() {
+                    ERROR!("Unable to close the reactor topics: {}", error);
+                }
+                return Err(error);
+            }
+        }
+        self.close()
+    }
+
+    /// Get a handle stopping `run` from another thread or a signal handler
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle::create(self.waker.clone(), self.shutdown.clone())
+    }
+
+    /// Close and remove all the topics in the order they were added
+    ///
+    /// # Errors
+    ///
+    /// * the first error returned by `Topic::close`, all the topics
+    ///   are closed anyway
+    pub fn close(&mut self) -> Result<()> {
+        let mut result = Ok(());
+        for slot in self.topics.iter_mut() {
+            if let Some(mut topic) = slot.take() {
+                result = result.and(topic.close());
+            }
+        }
+        result
+    }
+}
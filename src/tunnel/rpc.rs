@@ -0,0 +1,426 @@
+//! # //! Request/response layer over `ChannelData`
+//!
+//! An RPC message is a `ChannelData` message whose payload is an
+//! envelope:
+//!
+//! ```text
+//! magic (u8) | kind (u8) | request id (u32) | method size (u8) | method | payload
+//! ```
+//!
+//! The numbers are big endian. Requests are dispatched to the methods
+//! registered by name, the result of a method is sent back in a
+//! response with the same request id. A failed request is answered
+//! with a `ChannelError` message carrying a response envelope whose
+//! payload is the error text.
+//!
+//! The envelopes are neither compressed nor fragmented, they are sent
+//! and received without the payload flags of `compress` and `fragment`.
+//! The magic is not a valid flag, so an envelope is never taken for a
+//! flagged payload.
+//!
+//! `Rpc` can be used as the handler of a topic, or be embedded in
+//! another handler calling `Rpc::handle_event` on each event.
+//!
+//! **Author**: "Dany LE"
+//!
+use super::handler::TopicHandler;
+use super::{Bytes, CallbackEvent, Msg, MsgKind, Schedule, Signal, TimerId, Topic};
+use crate::error::{Error, Result};
+use crate::utils::{LogLevel, LOG};
+use crate::WARN;
+use bytes::{BufMut, BytesMut};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// First byte of an RPC envelope
+pub const RPC_MAGIC: u8 = 0x52;
+/// Size of the envelope header without the method name
+pub const RPC_HEADER_SIZE: usize = 7;
+
+/// Method called on a request
+///
+/// The method receives the topic, the client id and the request
+/// payload, and returns the response payload
+pub type RpcMethod = dyn FnMut(&mut Topic, u16, Bytes) -> Result<Bytes> + Send;
+/// Callback receiving the response of a request sent with `Rpc::call`
+pub type RpcReply = dyn FnOnce(&mut Topic, Result<Bytes>) -> Result<()> + Send;
+
+/// Kind of an RPC envelope
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum EnvelopeKind {
+    Request = 0,
+    Response = 1,
+}
+
+/// RPC envelope carried by the payload of a message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    pub kind: EnvelopeKind,
+    pub request_id: u32,
+    pub method: String,
+    pub payload: Bytes,
+}
+
+/// A request waiting for its response
+struct Pending {
+    client_id: u16,
+    timer: TimerId,
+    reply: Box<RpcReply>,
+}
+
+/// Request/response handler of a topic
+pub struct Rpc {
+    methods: HashMap<String, Box<RpcMethod>>,
+    pending: HashMap<u32, Pending>,
+    n_id: u32,
+    fallback: Option<Box<dyn TopicHandler>>,
+}
+
+impl Envelope {
+    /// Create a request envelope
+    ///
+    /// Arguments
+    ///
+    /// * `request_id` - the request id
+    /// * `method` - the method name
+    /// * `payload` - the request payload
+    pub fn request(request_id: u32, method: &str, payload: impl Into<Bytes>) -> Self {
+        Envelope {
+            kind: EnvelopeKind::Request,
+            request_id,
+            method: String::from(method),
+            payload: payload.into(),
+        }
+    }
+
+    /// Create a response envelope
+    ///
+    /// Arguments
+    ///
+    /// * `request_id` - the id of the request
+    /// * `payload` - the response payload or error text
+    pub fn response(request_id: u32, payload: impl Into<Bytes>) -> Self {
+        Envelope {
+            kind: EnvelopeKind::Response,
+            request_id,
+            method: String::new(),
+            payload: payload.into(),
+        }
+    }
+
+    /// Check if a payload starts with an RPC envelope
+    ///
+    /// Arguments
+    ///
+    /// * `data` - the payload
+    pub fn is_envelope(data: &[u8]) -> bool {
+        data.len() >= RPC_HEADER_SIZE && data[0] == RPC_MAGIC && data[1] <= 1
+    }
+
+    /// Serialize the envelope
+    ///
+    /// # Errors
+    ///
+    /// * `Error::Rpc` - the method name is longer than 255 bytes
+    pub fn encode(&self) -> Result<Bytes> {
+        let method = u8::try_from(self.method.len())
+            .map_err(|_| Error::Rpc(format!("Method name too long: {}", self.method)))?;
+        let mut buf =
+            BytesMut::with_capacity(RPC_HEADER_SIZE + self.method.len() + self.payload.len());
+        buf.put_u8(RPC_MAGIC);
+        buf.put_u8(self.kind as u8);
+        buf.put_u32(self.request_id);
+        buf.put_u8(method);
+        buf.put_slice(self.method.as_bytes());
+        buf.put_slice(&self.payload);
+        Ok(buf.freeze())
+    }
+
+    /// Parse an envelope, the payload shares the buffer of `data`
+    ///
+    /// Arguments
+    ///
+    /// * `data` - the message payload
+    ///
+    /// # Errors
+    ///
+    /// * `Error::Rpc` - the data is not a valid envelope
+    pub fn decode(data: &Bytes) -> Result<Self> {
+        if !Envelope::is_envelope(data) {
+            return Err(Error::Rpc(String::from("Invalid envelope header")));
+        }
+        let kind = match data[1] {
+            0 => EnvelopeKind::Request,
+            _ => EnvelopeKind::Response,
+        };
+        let request_id = u32::from_be_bytes([data[2], data[3], data[4], data[5]]);
+        let end = RPC_HEADER_SIZE + data[6] as usize;
+        if data.len() < end {
+            return Err(Error::Rpc(String::from("Truncated envelope")));
+        }
+        let method = std::str::from_utf8(&data[RPC_HEADER_SIZE..end])?;
+        Ok(Envelope {
+            kind,
+            request_id,
+            method: String::from(method),
+            payload: data.slice(end..),
+        })
+    }
+}
+
+impl Rpc {
+    /// Create new `Rpc` object without any method
+    pub fn new() -> Self {
+        Rpc {
+            methods: HashMap::new(),
+            pending: HashMap::new(),
+            n_id: 0,
+            fallback: None,
+        }
+    }
+
+    /// Register a method
+    ///
+    /// An error returned by the method is sent back to the client
+    ///
+    /// Arguments
+    ///
+    /// * `name` - the method name
+    /// * `method` - the method
+    pub fn register(
+        &mut self,
+        name: &str,
+        method: impl FnMut(&mut Topic, u16, Bytes) -> Result<Bytes> + Send + 'static,
+    ) {
+        let _ = self.methods.insert(String::from(name), Box::new(method));
+    }
+
+    /// Set the handler receiving all the events that are not RPC
+    /// messages, when `Rpc` is the handler of the topic
+    ///
+    /// Arguments
+    ///
+    /// * `handler` - the handler
+    pub fn set_fallback(&mut self, handler: impl TopicHandler + 'static) {
+        self.fallback = Some(Box::new(handler));
+    }
+
+    /// Number of requests waiting for a response
+    pub fn pending_requests(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Send a request to a client
+    ///
+    /// `reply` receives the response payload, `Error::Remote` if the
+    /// client answers with an error, or `Error::Timeout` if there is
+    /// no response before the timeout.
+    ///
+    /// Arguments
+    ///
+    /// * `topic` - the topic
+    /// * `client_id` - the client id
+    /// * `method` - the method name
+    /// * `payload` - the request payload
+    /// * `timeout` - the maximum time waited for the response
+    /// * `reply` - the callback receiving the response
+    ///
+    /// # Errors
+    ///
+    /// * `Error::Rpc` - the method name is too long
+    /// * any error returned by `Topic::write`
+    pub fn call(
+        &mut self,
+        topic: &mut Topic,
+        client_id: u16,
+        method: &str,
+        payload: impl Into<Bytes>,
+        timeout: Duration,
+        reply: impl FnOnce(&mut Topic, Result<Bytes>) -> Result<()> + Send + 'static,
+    ) -> Result<u32> {
+        let request_id = self.n_id;
+        let data = Envelope::request(request_id, method, payload).encode()?;
+        topic.write_unflagged(&Msg::create(MsgKind::ChannelData, 0, client_id, data))?;
+        self.n_id = self.n_id.wrapping_add(1);
+        let timer = topic.add_timer(Schedule::After(timeout));
+        let pending = Pending {
+            client_id,
+            timer,
+            reply: Box::new(reply),
+        };
+        let _ = self.pending.insert(request_id, pending);
+        Ok(request_id)
+    }
+
+    /// Process the RPC messages and the request timeouts
+    ///
+    /// The pending requests of a subscriber leaving the topic fail
+    /// with `Error::NotSubscribed`.
+    /// Return true if the event has been consumed
+    ///
+    /// Arguments
+    ///
+    /// * `evt` - the event
+    /// * `topic` - the topic receiving the event
+    ///
+    /// # Errors
+    ///
+    /// * any error returned by a reply callback or by `Topic::write`
+    pub fn handle_event(&mut self, evt: &CallbackEvent, topic: &mut Topic) -> Result<bool> {
+        match evt.signal {
+            Some(Signal::Timer(timer)) => return self.handle_timeout(topic, timer),
+            Some(Signal::Left(client_id)) => {
+                // the event is not consumed, the handler may need it too
+                self.handle_leave(topic, client_id)?;
+                return Ok(false);
+            }
+            _ => {}
+        }
+        let msg = match evt.msg {
+            Some(msg) => msg,
+            None => return Ok(false),
+        };
+        if !matches!(msg.kind, MsgKind::ChannelData | MsgKind::ChannelError)
+            || !Envelope::is_envelope(&msg.data)
+        {
+            return Ok(false);
+        }
+        let envelope = match Envelope::decode(&msg.data) {
+            Ok(envelope) => envelope,
+            Err(error) => {
+                WARN!("Drop RPC message from client {}: {}", msg.client_id, error);
+                return Ok(true);
+            }
+        };
+        match envelope.kind {
+            EnvelopeKind::Request if msg.kind == MsgKind::ChannelData => {
+                self.handle_request(topic, msg.client_id, envelope)?
+            }
+            EnvelopeKind::Response => self.handle_response(topic, msg, envelope)?,
+            _ => WARN!("Drop RPC request sent as error by {}", msg.client_id),
+        }
+        Ok(true)
+    }
+
+    /// Call the method of a request and send back the response
+    ///
+    /// Arguments
+    ///
+    /// * `topic` - the topic
+    /// * `client_id` - the client id
+    /// * `envelope` - the request
+    fn handle_request(
+        &mut self,
+        topic: &mut Topic,
+        client_id: u16,
+        envelope: Envelope,
+    ) -> Result<()> {
+        let result = match self.methods.get_mut(&envelope.method) {
+            Some(method) => method(topic, client_id, envelope.payload),
+            None => Err(Error::Rpc(format!("Unknown method: {}", envelope.method))),
+        };
+        let (kind, payload) = match result {
+            Ok(payload) => (MsgKind::ChannelData, payload),
+            Err(error) => (MsgKind::ChannelError, Bytes::from(error.to_string())),
+        };
+        let data = Envelope::response(envelope.request_id, payload).encode()?;
+        topic.write_unflagged(&Msg::create(kind, 0, client_id, data))
+    }
+
+    /// Pass a response to the callback of its request
+    ///
+    /// Arguments
+    ///
+    /// * `topic` - the topic
+    /// * `msg` - the message carrying the response
+    /// * `envelope` - the response
+    fn handle_response(&mut self, topic: &mut Topic, msg: &Msg, envelope: Envelope) -> Result<()> {
+        let pending = match self.pending.get(&envelope.request_id) {
+            Some(pending) if pending.client_id == msg.client_id => {
+                self.pending.remove(&envelope.request_id)
+            }
+            _ => None,
+        };
+        let pending = match pending {
+            Some(pending) => pending,
+            None => {
+                WARN!(
+                    "Drop RPC response {} from client {}: no such request",
+                    envelope.request_id,
+                    msg.client_id
+                );
+                return Ok(());
+            }
+        };
+        let _ = topic.cancel_timer(pending.timer);
+        let result = match msg.kind {
+            MsgKind::ChannelError => Err(Error::Remote(
+                String::from_utf8_lossy(&envelope.payload).into_owned(),
+            )),
+            _ => Ok(envelope.payload),
+        };
+        (pending.reply)(topic, result)
+    }
+
+    /// Fail the request whose timeout has expired
+    ///
+    /// Return false if the timer is not a request timeout
+    ///
+    /// Arguments
+    ///
+    /// * `topic` - the topic
+    /// * `timer` - the expired timer
+    fn handle_timeout(&mut self, topic: &mut Topic, timer: TimerId) -> Result<bool> {
+        let request_id = match self.pending.iter().find(|(_, p)| p.timer == timer) {
+            Some((request_id, _)) => *request_id,
+            None => return Ok(false),
+        };
+        if let Some(pending) = self.pending.remove(&request_id) {
+            (pending.reply)(topic, Err(Error::Timeout))?;
+        }
+        Ok(true)
+    }
+
+    /// Fail the pending requests of a subscriber leaving the topic
+    ///
+    /// Arguments
+    ///
+    /// * `topic` - the topic
+    /// * `client_id` - the client id
+    fn handle_leave(&mut self, topic: &mut Topic, client_id: u16) -> Result<()> {
+        let mut ids: Vec<u32> = self
+            .pending
+            .iter()
+            .filter(|(_, p)| p.client_id == client_id)
+            .map(|(request_id, _)| *request_id)
+            .collect();
+        ids.sort_unstable();
+        let mut result = Ok(());
+        for request_id in ids {
+            if let Some(pending) = self.pending.remove(&request_id) {
+                let _ = topic.cancel_timer(pending.timer);
+                result = result.and((pending.reply)(topic, Err(Error::NotSubscribed(client_id))));
+            }
+        }
+        result
+    }
+}
+
+impl Default for Rpc {
+    fn default() -> Self {
+        Rpc::new()
+    }
+}
+
+impl TopicHandler for Rpc {
+    fn on_event(&mut self, evt: &CallbackEvent, topic: &mut Topic) -> Result<()> {
+        if self.handle_event(evt, topic)? {
+            return Ok(());
+        }
+        match self.fallback.as_mut() {
+            Some(handler) => handler.on_event(evt, topic),
+            None => Ok(()),
+        }
+    }
+}
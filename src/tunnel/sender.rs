@@ -0,0 +1,107 @@
+//! # //! Cross-thread sending to a topic
+//!
+//! A `TopicSender` queues messages for a `Topic` from any thread.
+//! The topic is woken up through the waker of its poll object and
+//! writes the queued messages in its own thread, in `step`.
+//!
+//! **Author**: "Dany LE"
+//!
+use super::{Bytes, Msg};
+use crate::error::{Error, Result};
+use mio::Waker;
+use std::sync::mpsc::{SyncSender, TrySendError};
+use std::sync::Arc;
+
+/// Capacity of the queue between the senders and the topic
+pub const SENDER_QUEUE_SIZE: usize = 1024;
+
+/// Message queued by a `TopicSender`
+#[derive(Debug)]
+pub(super) enum Command {
+    /// Write a message as is
+    Write(Msg),
+    /// Send data to all the subscribers
+    Broadcast(Bytes),
+    /// Send data to a subscriber
+    SendTo(u16, Bytes),
+}
+
+/// Handle sending messages to a `Topic` from other threads
+///
+/// The handle can be cloned and sent to other threads. The messages
+/// are written by the topic in `step`, the errors of the writes are
+/// logged by the topic and the messages are dropped.
+#[derive(Debug, Clone)]
+pub struct TopicSender {
+    queue: SyncSender<Command>,
+    waker: Arc<Waker>,
+}
+
+impl TopicSender {
+    /// Create new `TopicSender` object
+    ///
+    /// Arguments
+    ///
+    /// * `queue` - the sending half of the topic queue
+    /// * `waker` - the waker of the poll object of the topic
+    pub(super) fn create(queue: SyncSender<Command>, waker: Arc<Waker>) -> Self {
+        TopicSender { queue, waker }
+    }
+
+    /// Queue a message to the tunnel
+    ///
+    /// Arguments
+    ///
+    /// * `msg` - a message
+    ///
+    /// # Errors
+    ///
+    /// * `Error::Congested` - the queue is full
+    /// * `Error::TopicDropped` - the topic no longer exists
+    /// * `Error::Io` - unable to wake up the topic
+    pub fn send(&self, msg: Msg) -> Result<()> {
+        self.queue(Command::Write(msg))
+    }
+
+    /// Queue data to all the subscribers of the topic
+    ///
+    /// Arguments
+    ///
+    /// * `data` - the payload
+    ///
+    /// # Errors
+    ///
+    /// See `send`
+    pub fn broadcast(&self, data: impl Into<Bytes>) -> Result<()> {
+        self.queue(Command::Broadcast(data.into()))
+    }
+
+    /// Queue data to a subscriber of the topic
+    ///
+    /// Arguments
+    ///
+    /// * `client_id` - the client id
+    /// * `data` - the payload
+    ///
+    /// # Errors
+    ///
+    /// See `send`
+    pub fn send_to(&self, client_id: u16, data: impl Into<Bytes>) -> Result<()> {
+        self.queue(Command::SendTo(client_id, data.into()))
+    }
+
+    /// Queue a command and wake up the topic
+    ///
+    /// Arguments
+    ///
+    /// * `command` - the command
+    fn queue(&self, command: Command) -> Result<()> {
+        match self.queue.try_send(command) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => return Err(Error::Congested),
+            Err(TrySendError::Disconnected(_)) => return Err(Error::TopicDropped),
+        }
+        self.waker.wake()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,46 @@
+//! # //! Shutdown of the run loops
+//!
+//! A `ShutdownHandle` stops `Topic::run` or `Reactor::run` from
+//! another thread or from a signal handler. The loop is woken up
+//! immediately through the waker of its poll object.
+//!
+//! **Author**: "Dany LE"
+//!
+use mio::Waker;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Handle stopping a run loop
+///
+/// The handle can be cloned and sent to other threads
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    waker: Arc<Waker>,
+    flag: Arc<AtomicBool>,
+}
+
+impl ShutdownHandle {
+    /// Create new `ShutdownHandle` object
+    ///
+    /// Arguments
+    ///
+    /// * `waker` - the waker of the poll object running the loop
+    /// * `flag` - the shutdown flag checked by the loop
+    pub(super) fn create(waker: Arc<Waker>, flag: Arc<AtomicBool>) -> Self {
+        ShutdownHandle { waker, flag }
+    }
+
+    /// Request the loop to stop
+    ///
+    /// The call only sets a flag and writes to the waker, it can
+    /// be used from a signal handler
+    pub fn shutdown(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+        let _ = self.waker.wake();
+    }
+
+    /// Check if the shutdown has been requested
+    pub fn is_shutdown(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+}
@@ -0,0 +1,166 @@
+//! # //! Subscribers of a channel
+//!
+//! `SubscriberMap` keeps the clients subscribed to a channel with
+//! their activity statistics and some application data.
+//!
+//! A `Topic` tracks its subscribers in a `SubscriberMap<SubscriberData>`.
+//! The data of a new subscriber is created by the function set with
+//! `Topic::set_subscriber_data` and is dropped with the subscriber. It
+//! is accessed with its concrete type through `Topic::subscriber_data`
+//! and `Topic::subscriber_data_mut`.
+//!
+//! **Author**: "Dany LE"
+//!
+use super::compress::Compression;
+use std::any::Any;
+use std::collections::hash_map;
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// Application data attached to the subscribers of a `Topic`
+pub type SubscriberData = Box<dyn Any + Send>;
+/// Function creating the application data of a new subscriber
+/// of a `Topic` from its client id
+pub type SubscriberInit = dyn FnMut(u16) -> SubscriberData + Send;
+
+/// A client subscribed to a channel
+#[derive(Debug)]
+pub struct Subscriber<T = ()> {
+    /// The client id
+    pub client_id: u16,
+    /// Time of the subscription
+    pub joined_at: Instant,
+    /// Time of the last message received from the client
+    pub last_activity: Instant,
+    /// Number of payload bytes sent to the client
+    pub bytes_sent: u64,
+    /// Number of payload bytes received from the client
+    pub bytes_received: u64,
+    /// Compression of the data exchanged with the client, set
+    /// once negotiated (see `compress`)
+    pub compression: Option<Compression>,
+    /// Application data
+    pub data: T,
+}
+
+/// Subscribers of a channel indexed by client id
+#[derive(Debug)]
+pub struct SubscriberMap<T = ()> {
+    subscribers: HashMap<u16, Subscriber<T>>,
+}
+
+impl<T> Subscriber<T> {
+    /// Create new `Subscriber` object
+    ///
+    /// Arguments
+    ///
+    /// * `client_id` - the client id
+    /// * `data` - the application data
+    pub fn create(client_id: u16, data: T) -> Self {
+        let now = Instant::now();
+        Subscriber {
+            client_id,
+            joined_at: now,
+            last_activity: now,
+            bytes_sent: 0,
+            bytes_received: 0,
+            compression: None,
+            data,
+        }
+    }
+}
+
+impl<T> SubscriberMap<T> {
+    /// Create an empty `SubscriberMap`
+    pub fn new() -> Self {
+        SubscriberMap {
+            subscribers: HashMap::new(),
+        }
+    }
+
+    /// Number of subscribers
+    pub fn len(&self) -> usize {
+        self.subscribers.len()
+    }
+
+    /// Check if there is no subscriber
+    pub fn is_empty(&self) -> bool {
+        self.subscribers.is_empty()
+    }
+
+    /// Check if a client is subscribed
+    ///
+    /// Arguments
+    ///
+    /// * `client_id` - the client id
+    pub fn contains(&self, client_id: u16) -> bool {
+        self.subscribers.contains_key(&client_id)
+    }
+
+    /// Get a subscriber
+    ///
+    /// Arguments
+    ///
+    /// * `client_id` - the client id
+    pub fn get(&self, client_id: u16) -> Option<&Subscriber<T>> {
+        self.subscribers.get(&client_id)
+    }
+
+    /// Get a mutable reference to a subscriber
+    ///
+    /// Arguments
+    ///
+    /// * `client_id` - the client id
+    pub fn get_mut(&mut self, client_id: u16) -> Option<&mut Subscriber<T>> {
+        self.subscribers.get_mut(&client_id)
+    }
+
+    /// Iterate over the subscribers in arbitrary order
+    pub fn iter(&self) -> hash_map::Values<'_, u16, Subscriber<T>> {
+        self.subscribers.values()
+    }
+
+    /// Iterate over the subscribers mutably in arbitrary order
+    pub fn iter_mut(&mut self) -> hash_map::ValuesMut<'_, u16, Subscriber<T>> {
+        self.subscribers.values_mut()
+    }
+
+    /// Client ids of the subscribers in arbitrary order
+    pub fn ids(&self) -> impl Iterator<Item = u16> + '_ {
+        self.subscribers.keys().copied()
+    }
+
+    /// Add a subscriber
+    ///
+    /// An already subscribed client is kept as is
+    ///
+    /// Arguments
+    ///
+    /// * `client_id` - the client id
+    /// * `data` - the application data of a new subscriber
+    pub fn insert(&mut self, client_id: u16, data: T) -> &mut Subscriber<T> {
+        self.subscribers
+            .entry(client_id)
+            .or_insert_with(|| Subscriber::create(client_id, data))
+    }
+
+    /// Remove a subscriber
+    ///
+    /// Arguments
+    ///
+    /// * `client_id` - the client id
+    pub fn remove(&mut self, client_id: u16) -> Option<Subscriber<T>> {
+        self.subscribers.remove(&client_id)
+    }
+
+    /// Remove all the subscribers
+    pub fn clear(&mut self) {
+        self.subscribers.clear();
+    }
+}
+
+impl<T> Default for SubscriberMap<T> {
+    fn default() -> Self {
+        SubscriberMap::new()
+    }
+}
@@ -0,0 +1,107 @@
+//! # //! Timers of a topic
+//!
+//! Timers are checked by `Topic::step`, the poll timeout is derived
+//! from the next deadline so that a timer fires on time even if no
+//! socket event happens.
+//!
+//! **Author**: "Dany LE"
+//!
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Identifier of a timer of a topic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TimerId(u64);
+
+/// When a timer fires
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schedule {
+    /// Fire once at the given time
+    At(Instant),
+    /// Fire once after the given delay
+    After(Duration),
+    /// Fire periodically with the given interval
+    Every(Duration),
+}
+
+/// Timers of a topic
+#[derive(Debug, Default)]
+pub(super) struct Timers {
+    timers: HashMap<TimerId, (Instant, Option<Duration>)>,
+    n_id: u64,
+}
+
+impl Timers {
+    /// Add a timer
+    ///
+    /// Arguments
+    ///
+    /// * `schedule` - when the timer fires
+    pub fn add(&mut self, schedule: Schedule) -> TimerId {
+        let now = Instant::now();
+        let timer = match schedule {
+            Schedule::At(deadline) => (deadline, None),
+            Schedule::After(delay) => (now + delay, None),
+            Schedule::Every(interval) => (now + interval, Some(interval)),
+        };
+        let id = TimerId(self.n_id);
+        self.n_id += 1;
+        let _ = self.timers.insert(id, timer);
+        id
+    }
+
+    /// Remove a timer, return false if the timer does not exist
+    ///
+    /// Arguments
+    ///
+    /// * `id` - the timer identifier
+    pub fn cancel(&mut self, id: TimerId) -> bool {
+        self.timers.remove(&id).is_some()
+    }
+
+    /// Deadline of the next timer
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.timers.values().map(|(deadline, _)| *deadline).min()
+    }
+
+    /// Get the timers due at `now` ordered by deadline
+    ///
+    /// Arguments
+    ///
+    /// * `now` - the current time
+    pub fn expired(&self, now: Instant) -> Vec<TimerId> {
+        let mut due: Vec<(Instant, TimerId)> = self
+            .timers
+            .iter()
+            .filter(|(_, (deadline, _))| *deadline <= now)
+            .map(|(id, (deadline, _))| (*deadline, *id))
+            .collect();
+        due.sort_by_key(|(deadline, id)| (*deadline, id.0));
+        due.into_iter().map(|(_, id)| id).collect()
+    }
+
+    /// Mark an expired timer as fired
+    ///
+    /// A one-shot timer is removed and a periodic timer is
+    /// rescheduled. A periodic timer fires only once even if
+    /// several intervals have elapsed.
+    /// Return false if the timer does not exist (e.g. cancelled)
+    ///
+    /// Arguments
+    ///
+    /// * `id` - the timer identifier
+    /// * `now` - the current time
+    pub fn fire(&mut self, id: TimerId, now: Instant) -> bool {
+        match self.timers.get_mut(&id) {
+            Some((deadline, Some(interval))) => {
+                *deadline += *interval;
+                if *deadline <= now {
+                    *deadline = now + *interval;
+                }
+                true
+            }
+            Some(_) => self.timers.remove(&id).is_some(),
+            None => false,
+        }
+    }
+}
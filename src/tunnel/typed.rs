@@ -0,0 +1,260 @@
+//! # //! Typed payloads of a topic
+//!
+//! `TypedTopic` serializes the values sent to the clients into
+//! `ChannelData` payloads and deserializes the payloads received
+//! from the clients with serde. A payload that can not be decoded
+//! is answered with a `ChannelError` message carrying the error text.
+//!
+//! This module is only available with at least one of the `json`,
+//! `cbor` and `msgpack` features, each one enabling its format.
+//!
+//! **Author**: "Dany LE"
+//!
+use super::handler::TopicHandler;
+use super::{Bytes, CallbackEvent, Msg, MsgKind, Topic};
+use crate::error::{Error, Result};
+use crate::utils::{LogLevel, LOG};
+use crate::WARN;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+
+/// Serialization format of the payloads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// JSON text
+    #[cfg(feature = "json")]
+    Json,
+    /// CBOR (RFC 8949)
+    #[cfg(feature = "cbor")]
+    Cbor,
+    /// MessagePack, structs are encoded as maps
+    #[cfg(feature = "msgpack")]
+    MsgPack,
+}
+
+/// Callback receiving the decoded payloads
+pub type ValueHandle<In> = dyn FnMut(&mut Topic, u16, In) -> Result<()> + Send;
+
+/// Topic handler decoding the `ChannelData` payloads
+///
+/// All the other events are passed to the fallback handler
+pub struct TypedHandler<In> {
+    format: Format,
+    handle: Box<ValueHandle<In>>,
+    fallback: Option<Box<dyn TopicHandler>>,
+}
+
+/// Topic sending and receiving serde values
+///
+/// `TypedTopic` dereferences to the wrapped `Topic`
+pub struct TypedTopic<In, Out> {
+    topic: Topic,
+    format: Format,
+    types: PhantomData<fn(Out) -> In>,
+}
+
+impl Format {
+    /// Serialize a value
+    ///
+    /// Arguments
+    ///
+    /// * `value` - the value
+    ///
+    /// # Errors
+    ///
+    /// * `Error::Serde` - the value can not be serialized
+    pub fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Bytes> {
+        let data = match *self {
+            #[cfg(feature = "json")]
+            Format::Json => serde_json::to_vec(value).map_err(serde_error)?,
+            #[cfg(feature = "cbor")]
+            Format::Cbor => {
+                let mut data = Vec::new();
+                ciborium::into_writer(value, &mut data).map_err(serde_error)?;
+                data
+            }
+            #[cfg(feature = "msgpack")]
+            Format::MsgPack => rmp_serde::to_vec_named(value).map_err(serde_error)?,
+        };
+        Ok(Bytes::from(data))
+    }
+
+    /// Deserialize a value
+    ///
+    /// Arguments
+    ///
+    /// * `data` - the payload
+    ///
+    /// # Errors
+    ///
+    /// * `Error::Serde` - the payload is not a valid value
+    pub fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T> {
+        match *self {
+            #[cfg(feature = "json")]
+            Format::Json => serde_json::from_slice(data).map_err(serde_error),
+            #[cfg(feature = "cbor")]
+            Format::Cbor => ciborium::from_reader(data).map_err(serde_error),
+            #[cfg(feature = "msgpack")]
+            Format::MsgPack => rmp_serde::from_slice(data).map_err(serde_error),
+        }
+    }
+}
+
+/// Convert an error of a serde format to `Error::Serde`
+///
+/// Arguments
+///
+/// * `error` - the error of the format
+fn serde_error(error: impl std::fmt::Display) -> Error {
+    Error::Serde(error.to_string())
+}
+
+impl<In: DeserializeOwned> TypedHandler<In> {
+    /// Create new `TypedHandler` object
+    ///
+    /// Arguments
+    ///
+    /// * `format` - the format of the payloads
+    /// * `handle` - the callback receiving the client id and the decoded value
+    pub fn new(
+        format: Format,
+        handle: impl FnMut(&mut Topic, u16, In) -> Result<()> + Send + 'static,
+    ) -> Self {
+        TypedHandler {
+            format,
+            handle: Box::new(handle),
+            fallback: None,
+        }
+    }
+
+    /// Set the handler receiving all the other events
+    ///
+    /// Arguments
+    ///
+    /// * `handler` - the handler
+    pub fn set_fallback(&mut self, handler: impl TopicHandler + 'static) {
+        self.fallback = Some(Box::new(handler));
+    }
+}
+
+impl<In: DeserializeOwned> TopicHandler for TypedHandler<In> {
+    fn on_event(&mut self, evt: &CallbackEvent, topic: &mut Topic) -> Result<()> {
+        let msg = match evt.msg {
+            Some(msg) if msg.kind == MsgKind::ChannelData => msg,
+            _ => {
+                return match self.fallback.as_mut() {
+                    Some(handler) => handler.on_event(evt, topic),
+                    None => Ok(()),
+                }
+            }
+        };
+        match self.format.decode(&msg.data) {
+            Ok(value) => (self.handle)(topic, msg.client_id, value),
+            Err(error) => {
+                WARN!("Invalid payload from client {}: {}", msg.client_id, error);
+                let reply = Msg::create(
+                    MsgKind::ChannelError,
+                    0,
+                    msg.client_id,
+                    error.to_string().into_bytes(),
+                );
+                topic.write(&reply)
+            }
+        }
+    }
+}
+
+impl<In, Out> TypedTopic<In, Out>
+where
+    In: DeserializeOwned + 'static,
+    Out: Serialize,
+{
+    /// Create new `TypedTopic` object
+    ///
+    /// Arguments
+    ///
+    /// * `topic` - the topic
+    /// * `format` - the format of the payloads
+    pub fn create(topic: Topic, format: Format) -> Self {
+        TypedTopic {
+            topic,
+            format,
+            types: PhantomData,
+        }
+    }
+
+    /// Format of the payloads
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Get the wrapped topic back
+    pub fn into_inner(self) -> Topic {
+        self.topic
+    }
+
+    /// Set the callback receiving the decoded values
+    ///
+    /// This replaces the handler of the topic, use `TypedHandler`
+    /// with a fallback handler to receive the other events
+    ///
+    /// Arguments
+    ///
+    /// * `handle` - the callback receiving the client id and the value
+    pub fn on_value(
+        &mut self,
+        handle: impl FnMut(&mut Topic, u16, In) -> Result<()> + Send + 'static,
+    ) {
+        self.topic
+            .set_handler(TypedHandler::new(self.format, handle));
+    }
+
+    /// Send a value to a subscriber of the channel
+    ///
+    /// Arguments
+    ///
+    /// * `client_id` - the client id
+    /// * `value` - the value
+    ///
+    /// # Errors
+    ///
+    /// * `Error::Serde` - the value can not be serialized
+    /// * any error returned by `Topic::send_to`
+    pub fn send_to(&mut self, client_id: u16, value: &Out) -> Result<()> {
+        let data = self.format.encode(value)?;
+        self.topic.send_to(client_id, data)
+    }
+
+    /// Send a value to all the subscribers of the channel
+    ///
+    /// The value is serialized once and shared by all the messages
+    ///
+    /// Arguments
+    ///
+    /// * `value` - the value
+    ///
+    /// # Errors
+    ///
+    /// * `Error::Serde` - the value can not be serialized
+    /// * any error returned by `Topic::broadcast`
+    pub fn broadcast(&mut self, value: &Out) -> Result<()> {
+        let data = self.format.encode(value)?;
+        self.topic.broadcast(data)
+    }
+}
+
+impl<In, Out> Deref for TypedTopic<In, Out> {
+    type Target = Topic;
+
+    fn deref(&self) -> &Topic {
+        &self.topic
+    }
+}
+
+impl<In, Out> DerefMut for TypedTopic<In, Out> {
+    fn deref_mut(&mut self) -> &mut Topic {
+        &mut self.topic
+    }
+}
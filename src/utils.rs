@@ -2,9 +2,9 @@
 //!
 //! **Author**: "Dany LE"
 //!
+use crate::error::{Error, Result};
 use libc;
 use std::collections::HashMap;
-use std::error::Error;
 use std::ffi::{CStr, CString};
 use std::fmt::Arguments;
 use std::fs::File;
@@ -21,18 +21,6 @@ pub const API_VERSION: &str = "0.1.0";
 /// Application name
 const DAEMON_NAME: &str = "antd-tunnel";
 
-/// Return an Error Result object from error string
-///
-#[macro_export]
-macro_rules! ERR {
-    ($x:expr) => {
-        Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("({}:{}): {}", file!(), line!(), $x),
-        ))
-    };
-}
-
 /// Macro for error log helper
 ///
 #[macro_export]
@@ -123,11 +111,13 @@ impl LOG {
     /// # Errors
     ///
     /// * `error` - All errors related to formated and C string manipulation
-    pub fn log(prefix: &str, level: &LogLevel, args: Arguments<'_>) -> Result<(), Box<dyn Error>> {
+    pub fn log(prefix: &str, level: &LogLevel, args: Arguments<'_>) -> Result<()> {
         use std::fmt::Write;
         let mut output = String::new();
         if output.write_fmt(args).is_err() {
-            return Err(ERR!("Unable to create format string from arguments"));
+            return Err(Error::Other(String::from(
+                "Unable to create format string from arguments",
+            )));
         }
         let log_fmt = format!("{}(v{}){}%s\n", DAEMON_NAME, API_VERSION, prefix);
         let fmt = CString::new(log_fmt.as_bytes())?;
@@ -180,8 +170,8 @@ pub fn on_exit(f: fn(n: i32) -> ()) {
 ///
 /// # Errors
 ///
-/// * `std error` - All error related to lib ffi calls
-pub fn get_username() -> Result<String, Box<dyn Error>> {
+/// * `Error::Privilege` - All error related to lib ffi calls
+pub fn get_username() -> Result<String> {
     let mut passwd_ptr = unsafe { mem::zeroed::<libc::passwd>() };
     let mut buf = vec![0; 1024];
     let mut result = ptr::null_mut::<libc::passwd>();
@@ -199,22 +189,24 @@ pub fn get_username() -> Result<String, Box<dyn Error>> {
     if result.is_null() {
         // There is no such user, or an error has occurred.
         // errno gets set if there’s an error.
-        return Err(ERR!("get_username: Result of getpwuid_r is NULL"));
+        return Err(Error::Privilege(String::from(
+            "get_username: Result of getpwuid_r is NULL",
+        )));
     }
 
     if result != &mut passwd_ptr {
         // The result of getpwuid_r should be its input passwd.
-        return Err(ERR!(
-            "get_username: result pointer of getpwuid_r does not match input passwd pointer"
-        ));
+        return Err(Error::Privilege(String::from(
+            "get_username: result pointer of getpwuid_r does not match input passwd pointer",
+        )));
     }
 
     if let Ok(username) = unsafe { CStr::from_ptr(passwd_ptr.pw_name) }.to_str() {
         Ok(String::from(username))
     } else {
-        Err(ERR!(
-            "get_username: Unable to extract username from passwd struct"
-        ))
+        Err(Error::Privilege(String::from(
+            "get_username: Unable to extract username from passwd struct",
+        )))
     }
 }
 
@@ -231,15 +223,17 @@ pub fn get_username() -> Result<String, Box<dyn Error>> {
 ///
 /// # Errors
 ///
+/// All the errors below are reported as `Error::Privilege`
+///
 /// * `Invalid user/group name` - The input user/group name is None
 /// * `getgrnam` - Error when calling the libc `getgrnam` function
 /// * `setgid` - Error when calling the libc `setgid` function
 /// * `getpwnam` - Error when calling the libc `getpwnam` function
 /// * `setuid` - Error when calling the libc `setuid` function
 /// * `CString from String` - Error creating `CString` from Rust `String`
-pub fn privdrop(optuser: Option<&String>, optgroup: Option<&String>) -> Result<(), Box<dyn Error>> {
+pub fn privdrop(optuser: Option<&String>, optgroup: Option<&String>) -> Result<()> {
     if optuser.is_none() && optgroup.is_none() {
-        return Err(ERR!("No user or group found!"));
+        return Err(Error::Privilege(String::from("No user or group found!")));
     }
     // the group id need to be set first, otherwise,
     // when the user privileges drop, it is unnable to
@@ -249,21 +243,23 @@ pub fn privdrop(optuser: Option<&String>, optgroup: Option<&String>) -> Result<(
         if let Ok(cstr) = CString::new(group.as_bytes()) {
             let p = unsafe { libc::getgrnam(cstr.as_ptr()) };
             if p.is_null() {
-                return Err(ERR!(format!(
+                return Err(Error::Privilege(format!(
                     "privdrop: Unable to getgrnam of group `{}`: {}",
                     group,
                     std::io::Error::last_os_error()
                 )));
             }
             if unsafe { libc::setgid((*p).gr_gid) } != 0 {
-                return Err(ERR!(format!(
+                return Err(Error::Privilege(format!(
                     "privdrop: Unable to setgid of group `{}`: {}",
                     group,
                     std::io::Error::last_os_error()
                 )));
             }
         } else {
-            return Err(ERR!("Cannot create CString from String (group)!"));
+            return Err(Error::Privilege(String::from(
+                "Cannot create CString from String (group)!",
+            )));
         }
     }
     // drop the user privileges
@@ -272,21 +268,23 @@ pub fn privdrop(optuser: Option<&String>, optgroup: Option<&String>) -> Result<(
         if let Ok(cstr) = CString::new(user.as_bytes()) {
             let p = unsafe { libc::getpwnam(cstr.as_ptr()) };
             if p.is_null() {
-                return Err(ERR!(format!(
+                return Err(Error::Privilege(format!(
                     "privdrop: Unable to getpwnam of user `{}`: {}",
                     user,
                     std::io::Error::last_os_error()
                 )));
             }
             if unsafe { libc::setuid((*p).pw_uid) } != 0 {
-                return Err(ERR!(format!(
+                return Err(Error::Privilege(format!(
                     "privdrop: Unable to setuid of user ``{}`: {}",
                     user,
                     std::io::Error::last_os_error()
                 )));
             }
         } else {
-            return Err(ERR!("Cannot create CString from String (user)!"));
+            return Err(Error::Privilege(String::from(
+                "Cannot create CString from String (user)!",
+            )));
         }
     }
     Ok(())
@@ -307,13 +305,13 @@ pub fn privdrop(optuser: Option<&String>, optgroup: Option<&String>) -> Result<(
 ///
 /// # Errors
 ///
-/// * `read_config` - Unable to read config file
-pub fn read_config(file: &str) -> Result<HashMap<String, String>, Box<dyn Error>> {
+/// * `Error::Config` - Unable to read config file
+pub fn read_config(file: &str) -> Result<HashMap<String, String>> {
     if let Ok(f) = File::open(file) {
         let mut map = HashMap::new();
         let buf = BufReader::new(f);
         buf.lines()
-            .filter_map(std::result::Result::ok)
+            .map_while(std::result::Result::ok)
             .filter(|s| {
                 if let Some(ch) = s.trim_start().chars().next() {
                     ch != '#'
@@ -331,7 +329,10 @@ pub fn read_config(file: &str) -> Result<HashMap<String, String>, Box<dyn Error>
             });
         Ok(map)
     } else {
-        Err(ERR!(format!("Unable to open config file {}", file)))
+        Err(Error::Config(format!(
+            "Unable to open config file {}",
+            file
+        )))
     }
 }
 
@@ -405,15 +406,9 @@ pub fn error_string(error: Box<dyn std::any::Any + Send>) -> String {
 ///
 /// # Errors
 ///
-/// * `std io error` - conversion error
-pub fn string_from_u8(data: &[u8]) -> Result<String, Box<dyn Error>> {
-    match std::str::from_utf8(data) {
-        Ok(s) => Ok(String::from(s)),
-        Err(error) => Err(ERR!(format!(
-            "str_from_u8: {}",
-            error_string(Box::new(error))
-        ))),
-    }
+/// * `Error::Utf8` - conversion error
+pub fn string_from_u8(data: &[u8]) -> Result<String> {
+    Ok(String::from(std::str::from_utf8(data)?))
 }
 
 /// Return the number of bytes available to read from a raw fd.